@@ -0,0 +1,189 @@
+use http::Method;
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    Deny,
+}
+
+/// Method + path rule; rules are checked in order and the first match wins,
+/// requests matching no rule are allowed. Paths are compared normalized and
+/// case-insensitively, see `normalize_path`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AccessRule {
+    /// Empty means any method
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// `*` matches within a path segment, `**` across segments; `/ws/**` covers `/ws` too
+    pub path: String,
+    pub action: Action,
+}
+
+impl AccessRule {
+    fn matches(&self, method: &Method, path: &str) -> bool {
+        let method_matches = self.methods.is_empty()
+            || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method.as_str()));
+        method_matches && glob_match(self.path.as_bytes(), path.as_bytes())
+    }
+}
+
+pub fn is_allowed(rules: &[AccessRule], method: &Method, path: &str) -> bool {
+    if rules.is_empty() {
+        return true;
+    }
+    let path = normalize_path(path);
+    rules
+        .iter()
+        .find(|rule| rule.matches(method, &path))
+        .map(|rule| rule.action == Action::Allow)
+        .unwrap_or(true)
+}
+
+pub fn glob_match(pattern: &[u8], path: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((b'/', b"**")) if path.is_empty() => true,
+        Some((b'*', rest)) => match rest.strip_prefix(b"*") {
+            Some(rest) => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+            None => (0..=path.len())
+                .take_while(|&i| i == 0 || path[i - 1] != b'/')
+                .any(|i| glob_match(rest, &path[i..])),
+        },
+        Some((c, rest)) => {
+            path.first().is_some_and(|p| p.eq_ignore_ascii_case(c)) && glob_match(rest, &path[1..])
+        },
+    }
+}
+
+/// Spells `path` the way the upstream resolves it, so rules can't be sidestepped by
+/// `/api/%65xport`, `/api//export` or `/api/x/../export`: unreserved characters are
+/// percent-decoded, empty and `.` segments dropped and `..` segments resolved
+pub fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = match bytes.get(i..i + 3) {
+            Some([b'%', high, low]) => std::str::from_utf8(&[*high, *low])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .filter(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'.' | b'_' | b'~')),
+            _ => None,
+        };
+        match escaped {
+            Some(c) => {
+                decoded.push(c);
+                i += 3;
+            },
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            },
+        }
+    }
+    let decoded = String::from_utf8_lossy(&decoded);
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {},
+            ".." => {
+                segments.pop();
+            },
+            segment => segments.push(segment),
+        }
+    }
+    let trailing_slash = decoded.ends_with('/') && !segments.is_empty();
+    let mut normalized = format!("/{}", segments.join("/"));
+    if trailing_slash {
+        normalized.push('/');
+    }
+    normalized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(methods: &[&str], path: &str, action: Action) -> AccessRule {
+        AccessRule {
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+            path: path.to_owned(),
+            action,
+        }
+    }
+
+    #[test]
+    fn glob_single_star_stays_within_a_segment() {
+        assert!(glob_match(b"/api/*/run", b"/api/flows/run"));
+        assert!(!glob_match(b"/api/*/run", b"/api/flows/1/run"));
+        assert!(glob_match(b"/api/*", b"/api/"));
+    }
+
+    #[test]
+    fn glob_double_star_crosses_segments() {
+        assert!(glob_match(b"/api/**", b"/api/flows/1/run"));
+        assert!(glob_match(b"/api/**", b"/api/"));
+        assert!(glob_match(b"/api/**", b"/api"));
+        assert!(!glob_match(b"/api/**", b"/apiary"));
+    }
+
+    #[test]
+    fn glob_ignores_case() {
+        assert!(glob_match(b"/api/export/**", b"/API/Export/x"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = [
+            rule(&["GET"], "/api/**", Action::Allow),
+            rule(&[], "/api/**", Action::Deny),
+        ];
+        assert!(is_allowed(&rules, &Method::GET, "/api/flows"));
+        assert!(!is_allowed(&rules, &Method::POST, "/api/flows"));
+    }
+
+    #[test]
+    fn narrower_rule_listed_first_overrides_broader_one() {
+        let rules = [
+            rule(&[], "/api/export/**", Action::Deny),
+            rule(&[], "/api/**", Action::Allow),
+        ];
+        assert!(!is_allowed(&rules, &Method::GET, "/api/export/x"));
+        assert!(is_allowed(&rules, &Method::GET, "/api/flows"));
+    }
+
+    #[test]
+    fn unmatched_requests_are_allowed() {
+        let rules = [rule(&["DELETE"], "/api/**", Action::Deny)];
+        assert!(is_allowed(&rules, &Method::GET, "/api/flows"));
+        assert!(is_allowed(&[], &Method::DELETE, "/api/flows"));
+    }
+
+    #[test]
+    fn other_spellings_of_a_denied_path_are_denied() {
+        let rules = [rule(&[], "/api/export/**", Action::Deny)];
+        for path in [
+            "/api/export/x",
+            "/API/export/x",
+            "/api/%65xport/x",
+            "/api/%45XPORT/x",
+            "/api//export/x",
+            "/api/./export/x",
+            "/api/flows/../export/x",
+            "/api/flows/%2e%2e/export/x",
+        ] {
+            assert!(!is_allowed(&rules, &Method::GET, path), "{} was allowed", path);
+        }
+    }
+
+    #[test]
+    fn normalize_keeps_reserved_escapes() {
+        assert_eq!(normalize_path("/a%2Fb/%7e"), "/a%2Fb/~");
+        assert_eq!(normalize_path("//a///b/"), "/a/b/");
+        assert_eq!(normalize_path("/../.."), "/");
+        assert_eq!(normalize_path("/a/%zz"), "/a/%zz");
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Extension, Query},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json,
+    Router,
+};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::{
+    cfg::Cfg,
+    router::trace_layer,
+    state::{State, Stats, WebSocketInfo},
+};
+
+#[derive(Serialize)]
+pub struct InstanceStats {
+    instance: String,
+    #[serde(flatten)]
+    stats: Stats,
+}
+
+pub async fn stats(
+    Extension(cfg): Extension<Arc<Cfg>>,
+    Extension(state): Extension<Arc<State>>
+) -> Json<InstanceStats> {
+    Json(InstanceStats { instance: cfg.instance_name.clone(), stats: state.stats() })
+}
+
+/// The effective config, as loaded and normalized, with credential headers redacted
+pub async fn config(Extension(cfg): Extension<Arc<Cfg>>) -> Response {
+    Json(&*cfg).into_response()
+}
+
+pub async fn websockets(Extension(state): Extension<Arc<State>>) -> Json<Vec<WebSocketInfo>> {
+    Json(state.websockets())
+}
+
+#[derive(Deserialize)]
+pub struct MaintenanceParams {
+    enabled: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub struct Maintenance {
+    maintenance: bool,
+}
+
+pub async fn get_maintenance(Extension(state): Extension<Arc<State>>) -> Json<Maintenance> {
+    Json(Maintenance { maintenance: state.maintenance() })
+}
+
+/// Sets maintenance mode from `?enabled=`, or toggles it when the parameter is missing
+pub async fn set_maintenance(
+    Extension(state): Extension<Arc<State>>,
+    Query(params): Query<MaintenanceParams>
+) -> Json<Maintenance> {
+    let enabled = params.enabled.unwrap_or(!state.maintenance());
+    state.set_maintenance(enabled);
+    tracing::warn!("Maintenance mode {}", if enabled { "enabled" } else { "disabled" });
+    Json(Maintenance { maintenance: enabled })
+}
+
+#[derive(Deserialize)]
+pub struct RouteParams {
+    prefix: Option<String>,
+}
+
+pub async fn get_disabled_routes(Extension(state): Extension<Arc<State>>) -> Json<Vec<String>> {
+    Json(state.disabled_routes())
+}
+
+pub async fn disable_route(
+    Extension(state): Extension<Arc<State>>,
+    Query(params): Query<RouteParams>
+) -> Result<Json<Vec<String>>, StatusCode> {
+    let prefix = params.prefix.ok_or(StatusCode::BAD_REQUEST)?;
+    tracing::warn!("Disabling route {}", prefix);
+    state.disable_route(prefix);
+    Ok(Json(state.disabled_routes()))
+}
+
+/// Re-enables the route given by `?prefix=`, or all routes when the parameter is missing
+pub async fn enable_route(
+    Extension(state): Extension<Arc<State>>,
+    Query(params): Query<RouteParams>
+) -> Json<Vec<String>> {
+    match params.prefix {
+        Some(prefix) => {
+            tracing::warn!("Enabling route {}", prefix);
+            state.enable_route(&prefix);
+        },
+        None => {
+            tracing::warn!("Enabling all routes");
+            state.enable_all_routes();
+        },
+    }
+    Json(state.disabled_routes())
+}
+
+/// Management endpoints, served on their own listener so the proxy port
+/// never exposes them
+pub fn get_admin_router(cfg: Arc<Cfg>, state: Arc<State>) -> Router {
+    Router::new()
+        .route("/__stats", get(stats))
+        .route("/__config", get(config))
+        .route("/__ws", get(websockets))
+        .route("/__admin/maintenance", get(get_maintenance).post(set_maintenance))
+        .route("/__admin/routes", get(get_disabled_routes).post(disable_route).delete(enable_route))
+        .layer(Extension(state))
+        .layer(trace_layer(&cfg))
+        .layer(Extension(cfg))
+}
@@ -0,0 +1,81 @@
+use std::{
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use axum::body::Body;
+use bytes::Bytes;
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+
+type OnComplete = Box<dyn FnOnce(u64) + Send>;
+
+/// Passes a body through untouched, reporting its size once it has been fully read;
+/// bodies that error or get dropped before their end aren't reported
+pub struct CountingBody {
+    inner: Body,
+    total: u64,
+    /// Known length, for bodies that can't tell they're done before being polled again
+    expected: Option<u64>,
+    on_complete: Option<OnComplete>,
+}
+
+impl CountingBody {
+    fn complete(&mut self) {
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete(self.total);
+        }
+    }
+}
+
+impl HttpBody for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let frame = ready!(Pin::new(&mut self.inner).poll_frame(cx));
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    self.total += data.len() as u64;
+                }
+                // with a known length hyper stops polling after the last byte
+                if self.inner.is_end_stream() || Some(self.total) == self.expected {
+                    self.complete();
+                }
+            },
+            Some(Err(_)) => self.on_complete = None,
+            None => self.complete(),
+        }
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for CountingBody {
+    fn drop(&mut self) {
+        // empty bodies are never polled at all
+        if self.inner.is_end_stream() || Some(self.total) == self.expected {
+            self.complete();
+        }
+    }
+}
+
+/// Wraps `body` to call `on_complete` with its size in bytes, see `CountingBody`
+pub fn count_bytes(body: Body, on_complete: impl FnOnce(u64) + Send + 'static) -> Body {
+    Body::new(CountingBody {
+        expected: body.size_hint().exact(),
+        inner: body,
+        total: 0,
+        on_complete: Some(Box::new(on_complete)),
+    })
+}
@@ -1,15 +1,491 @@
 
-use std::{sync::Arc, path::PathBuf};
-use config::{Config, FileFormat, File};
-use serde_derive::Deserialize;
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr}, sync::Arc, path::PathBuf};
+use config::{Config, FileFormat, File, Source};
+use anyhow::Context;
+use http::{uri::Authority, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use serde::{de::Error, ser::SerializeMap, Deserializer, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use tracing::Level;
 
-#[derive(Debug, Deserialize)]
+use crate::{access::AccessRule, headers::is_sensitive};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StaticFallback {
+    /// Plain 404 from `ServeDir`
+    #[default]
+    Default,
+    /// Forward the request to the upstream host
+    Proxy,
+    /// Respond 404 with the given HTML page
+    Page(String),
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Favicon {
+    /// Forward `/favicon.ico` like any other request
+    #[default]
+    Proxy,
+    /// Respond 204 without a body
+    NoContent,
+    /// Serve the given file
+    File(String),
+}
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UserAgentMode {
+    /// Replace whatever the client sent
+    #[default]
+    Set,
+    /// Add after the client's User-Agent, separated by a space
+    Append,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct UserAgent {
+    #[serde(deserialize_with = "deserialize_header_value", serialize_with = "serialize_header_value")]
+    pub value: HeaderValue,
+    #[serde(default)]
+    pub mode: UserAgentMode,
+}
+
+/// Which way a WebSocket message travels
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    #[default]
+    ToClient,
+    ToUpstream,
+    Both,
+}
+
+impl Direction {
+    pub fn includes(self, direction: Direction) -> bool {
+        self == Direction::Both || self == direction
+    }
+}
+
+/// Find/replace on WebSocket text frames, binary frames are never touched
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TextReplacement {
+    pub find: String,
+    pub replace: String,
+    #[serde(default)]
+    pub direction: Direction,
+}
+
+/// Maps the inbound WebSocket route path to the upstream one
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+pub struct PathRewrite {
+    /// Removed from the front of the path when present
+    pub strip_prefix: Option<String>,
+    /// Put in front of the path after stripping
+    pub prefix: Option<String>,
+}
+
+impl PathRewrite {
+    pub fn apply(&self, path: &str) -> String {
+        let path = self.strip_prefix
+            .as_deref()
+            .and_then(|strip| path.strip_prefix(strip))
+            .unwrap_or(path);
+        let path = match &self.prefix {
+            Some(prefix) => format!("{}{}", prefix.trim_end_matches('/'), path),
+            None => path.to_owned(),
+        };
+        if path.starts_with('/') { path } else { format!("/{}", path) }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Replacement {
+    pub find: String,
+    pub replace: String,
+}
+
+/// Find/replace on proxied HTML, CSS and JavaScript responses
+#[derive(Debug, Deserialize, Serialize)]
+pub struct BodyRewrite {
+    pub replacements: Vec<Replacement>,
+    /// Larger bodies are streamed through untouched
+    #[serde(default = "default_rewrite_max_body_bytes")]
+    pub max_body_bytes: usize,
+}
+
+impl BodyRewrite {
+    pub fn apply(&self, text: String) -> String {
+        self.replacements.iter().fold(text, |text, replacement| {
+            if text.contains(&replacement.find) {
+                text.replace(&replacement.find, &replacement.replace)
+            } else {
+                text
+            }
+        })
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct UpstreamProbe {
+    pub method: String,
+    pub path: String,
+    /// Per attempt, an upstream that doesn't answer in time counts as down
+    pub timeout_ms: u64,
+    /// Keep `/readyz` at 503 until the probe succeeds
+    pub block_readiness: bool,
+}
+
+impl Default for UpstreamProbe {
+    fn default() -> Self {
+        Self {
+            method: "HEAD".into(),
+            path: "/".into(),
+            timeout_ms: 5000,
+            block_readiness: false,
+        }
+    }
+}
+
+/// Levels of the events `router::trace_layer` emits for every request
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct TraceLevels {
+    /// Finished responses with status and latency
+    #[serde(deserialize_with = "deserialize_level", serialize_with = "serialize_level")]
+    pub response: Level,
+    /// 5xx responses and failed requests
+    #[serde(deserialize_with = "deserialize_level", serialize_with = "serialize_level")]
+    pub failure: Level,
+}
+
+impl Default for TraceLevels {
+    fn default() -> Self {
+        Self {
+            response: Level::DEBUG,
+            failure: Level::ERROR,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub struct Cfg {
+    #[serde(default = "default_bind", deserialize_with = "deserialize_bind")]
+    pub bind: IpAddr,
     pub port: u16,
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    #[serde(default)]
+    pub bind_retries: u32,
+    #[serde(default = "default_bind_retry_delay_ms")]
+    pub bind_retry_delay_ms: u64,
+    pub max_connections: Option<usize>,
+    pub client_idle_timeout_secs: Option<u64>,
     pub sourcedata: String,
     pub help: String,
-    pub host: String,
+    #[serde(default = "default_instance_name")]
+    pub instance_name: String,
+    /// Upstream `host[:port]`s, requests are spread across them round-robin
+    #[serde(deserialize_with = "deserialize_hosts")]
+    pub host: Vec<String>,
+    pub upstream_port: Option<u16>,
+    pub ws_host: Option<String>,
+    #[serde(default)]
+    pub ws_path: PathRewrite,
+    pub shadow_host: Option<String>,
+    #[serde(default = "default_ws_buffer_capacity")]
+    pub ws_buffer_capacity: usize,
+    pub ws_send_timeout_ms: Option<u64>,
+    #[serde(default = "default_ws_close_timeout_ms")]
+    pub ws_close_timeout_ms: u64,
+    #[serde(default)]
+    pub ws_text_replacements: Vec<TextReplacement>,
     pub pagrid: bool,
+    #[serde(default, alias = "allow_insecure_tls")]
+    pub insecure_tls: bool,
+    pub ca_bundle: Option<PathBuf>,
+    #[serde(alias = "force_exit_secs")]
+    pub shutdown_timeout_secs: Option<u64>,
+    pub request_timeout_ms: Option<u64>,
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub retries: u32,
+    #[serde(default)]
+    pub buffer_request_body: bool,
+    #[serde(default = "default_max_request_body_bytes")]
+    pub max_request_body_bytes: usize,
+    #[serde(default)]
+    pub static_fallback: StaticFallback,
+    #[serde(default)]
+    pub precompressed_static: bool,
+    #[serde(default)]
+    pub static_catch_all: bool,
+    #[serde(default)]
+    pub favicon: Favicon,
+    #[serde(default)]
+    pub gzip_request_paths: Vec<String>,
+    #[serde(default)]
+    pub h2c: bool,
+    pub maintenance_page: Option<String>,
+    pub error_page: Option<String>,
+    #[serde(default = "default_max_header_count")]
+    pub max_header_count: usize,
+    #[serde(default = "default_max_header_value_len")]
+    pub max_header_value_len: usize,
+    pub admin_port: Option<u16>,
+    #[serde(default)]
+    pub access_rules: Vec<AccessRule>,
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    pub alpn_protocols: Option<Vec<String>>,
+    #[serde(default)]
+    pub http2: bool,
+    #[serde(default)]
+    pub decompress_responses: bool,
+    #[serde(default)]
+    pub decompress_requests: bool,
+    pub buffer_threshold_bytes: Option<u64>,
+    pub body_rewrite: Option<BodyRewrite>,
+    #[serde(default)]
+    pub strip_response_headers: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_headers", serialize_with = "serialize_headers")]
+    pub response_headers: HeaderMap,
+    #[serde(default)]
+    pub trace_levels: TraceLevels,
+    #[serde(default)]
+    pub log_responses: bool,
+    #[serde(default)]
+    pub log_headers: bool,
+    #[serde(default)]
+    pub log_sensitive: bool,
+    #[serde(default)]
+    pub upstream_probe: UpstreamProbe,
+    pub user_agent: Option<UserAgent>,
+    #[serde(default)]
+    pub answer_options: bool,
+    #[serde(default)]
+    pub forwarded_header: bool,
+    #[serde(
+        default,
+        deserialize_with = "deserialize_status_rewrites",
+        serialize_with = "serialize_status_rewrites"
+    )]
+    pub status_rewrites: HashMap<StatusCode, StatusCode>,
+}
+
+impl Cfg {
+    /// Names of the optional behaviours switched on, for the startup summary;
+    /// values are left out as some (headers, pages) may be sensitive
+    pub fn enabled_features(&self) -> Vec<&'static str> {
+        [
+            ("ca_bundle", self.ca_bundle.is_some()),
+            ("h2c", self.h2c),
+            ("http2", self.http2),
+            ("proxy_protocol", self.proxy_protocol),
+            ("admin", self.admin_port.is_some()),
+            ("shadow_host", self.shadow_host.is_some()),
+            ("access_rules", !self.access_rules.is_empty()),
+            ("maintenance_page", self.maintenance_page.is_some()),
+            ("error_page", self.error_page.is_some()),
+            ("static_fallback", !matches!(self.static_fallback, StaticFallback::Default)),
+            ("precompressed_static", self.precompressed_static),
+            ("static_catch_all", self.static_catch_all),
+            ("favicon", !matches!(self.favicon, Favicon::Proxy)),
+            ("gzip_request_paths", !self.gzip_request_paths.is_empty()),
+            ("decompress_responses", self.decompress_responses),
+            ("decompress_requests", self.decompress_requests),
+            ("body_rewrite", self.body_rewrite.is_some()),
+            ("strip_response_headers", !self.strip_response_headers.is_empty()),
+            ("response_headers", !self.response_headers.is_empty()),
+            ("user_agent", self.user_agent.is_some()),
+            ("request_timeout", self.request_timeout_ms.is_some()),
+            ("retries", self.retries > 0),
+            ("buffer_request_body", self.buffer_request_body),
+            ("readiness_probe", self.upstream_probe.block_readiness),
+            ("log_responses", self.log_responses),
+            ("log_headers", self.log_headers),
+            ("answer_options", self.answer_options),
+            ("forwarded_header", self.forwarded_header),
+            ("ws_text_replacements", !self.ws_text_replacements.is_empty()),
+            ("status_rewrites", !self.status_rewrites.is_empty()),
+        ]
+        .into_iter()
+        .filter(|(_, enabled)| *enabled)
+        .map(|(name, _)| name)
+        .collect()
+    }
+}
+
+fn deserialize_headers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderMap, D::Error> {
+    <HashMap<String, String> as serde::Deserialize>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, value)| {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .map_err(|e| D::Error::custom(format!("invalid header name {}: {}", name, e)))?;
+            let value = HeaderValue::from_str(&value)
+                .map_err(|e| D::Error::custom(format!("invalid value for header {}: {}", name, e)))?;
+            Ok((name, value))
+        })
+        .collect()
+}
+
+/// Credential-carrying headers come out redacted, see `/__config`
+fn serialize_headers<S: Serializer>(headers: &HeaderMap, serializer: S) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(headers.len()))?;
+    for (name, value) in headers {
+        if is_sensitive(name) {
+            map.serialize_entry(name.as_str(), "<redacted>")?;
+        } else {
+            map.serialize_entry(name.as_str(), &String::from_utf8_lossy(value.as_bytes()))?;
+        }
+    }
+    map.end()
+}
+
+fn serialize_header_value<S: Serializer>(value: &HeaderValue, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&String::from_utf8_lossy(value.as_bytes()))
+}
+
+fn serialize_status_rewrites<S: Serializer>(
+    rewrites: &HashMap<StatusCode, StatusCode>,
+    serializer: S
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(rewrites.iter().map(|(from, to)| (from.as_u16(), to.as_u16())))
+}
+
+fn deserialize_status_rewrites<'de, D: Deserializer<'de>>(
+    deserializer: D
+) -> Result<HashMap<StatusCode, StatusCode>, D::Error> {
+    let parse = |code: &str| StatusCode::from_bytes(code.as_bytes())
+        .map_err(|e| D::Error::custom(format!("invalid status code {}: {}", code, e)));
+    // map keys always come out of the config crate as strings
+    <HashMap<String, String> as serde::Deserialize>::deserialize(deserializer)?
+        .iter()
+        .map(|(from, to)| Ok((parse(from)?, parse(to)?)))
+        .collect()
+}
+
+fn deserialize_header_value<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HeaderValue, D::Error> {
+    let value = <String as serde::Deserialize>::deserialize(deserializer)?;
+    HeaderValue::from_str(&value)
+        .map_err(|e| D::Error::custom(format!("invalid header value {:?}: {}", value, e)))
+}
+
+/// One `host[:port]` or a list of them
+fn deserialize_hosts<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Hosts {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    let hosts = match <Hosts as serde::Deserialize>::deserialize(deserializer)? {
+        Hosts::One(host) => vec![host],
+        Hosts::Many(hosts) => hosts,
+    };
+    if hosts.is_empty() {
+        return Err(D::Error::custom("host needs at least one upstream"));
+    }
+    Ok(hosts)
+}
+
+fn deserialize_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Level, D::Error> {
+    let level = <String as serde::Deserialize>::deserialize(deserializer)?;
+    level.parse()
+        .map_err(|_| D::Error::custom(format!("invalid log level {:?}", level)))
+}
+
+fn serialize_level<S: Serializer>(level: &Level, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(level)
+}
+
+fn deserialize_bind<'de, D: Deserializer<'de>>(deserializer: D) -> Result<IpAddr, D::Error> {
+    let bind = <String as serde::Deserialize>::deserialize(deserializer)?;
+    bind.trim().parse()
+        .map_err(|_| D::Error::custom(format!("bind {:?} is not an IPv4 or IPv6 address", bind)))
+}
+
+fn default_instance_name() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+fn default_rewrite_max_body_bytes() -> usize {
+    4 * 1024 * 1024
+}
+
+fn default_bind() -> IpAddr {
+    IpAddr::V4(Ipv4Addr::LOCALHOST)
+}
+
+fn default_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_request_body_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
+}
+
+fn default_bind_retry_delay_ms() -> u64 {
+    500
+}
+
+fn default_ws_buffer_capacity() -> usize {
+    64
+}
+
+fn default_ws_close_timeout_ms() -> u64 {
+    5000
+}
+
+fn default_max_header_count() -> usize {
+    100
+}
+
+fn default_max_header_value_len() -> usize {
+    16 * 1024
+}
+
+/// Reduces `https://backend:5043/` style values to the bare `host[:port]` the proxy
+/// formats its upstream URIs with
+fn normalize_host(field: &str, value: &str) -> anyhow::Result<String> {
+    let rest = match value.trim().split_once("://") {
+        Some((scheme, rest)) if scheme.eq_ignore_ascii_case("https") => rest,
+        Some((scheme, _)) => anyhow::bail!(
+            "{} {:?}: the upstream is always reached over https, {}:// isn't supported", field, value, scheme
+        ),
+        None => value.trim(),
+    };
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    if !path.is_empty() {
+        anyhow::bail!("{} {:?}: only host[:port] is supported, not a path", field, value);
+    }
+    let authority: Authority = authority
+        .parse()
+        .with_context(|| format!("{} {:?} is not a valid host[:port]", field, value))?;
+    if authority.as_str().contains('@') {
+        anyhow::bail!("{} {:?}: credentials in the host aren't supported", field, value);
+    }
+
+    if authority.as_str() != value {
+        tracing::warn!("Using {} {} instead of {:?}", field, authority, value);
+    }
+    Ok(authority.to_string())
+}
+
+/// Appends `port` to a normalized `host[:port]` that doesn't name one already
+fn with_default_port(host: String, port: Option<u16>) -> String {
+    match (port, host.parse::<Authority>()) {
+        (Some(port), Ok(authority)) if authority.port().is_none() => format!("{}:{}", host, port),
+        _ => host,
+    }
 }
 
 pub fn get_config(source: Option<PathBuf>) -> anyhow::Result<Arc<Cfg>> {
@@ -18,10 +494,98 @@ pub fn get_config(source: Option<PathBuf>) -> anyhow::Result<Arc<Cfg>> {
     } else {
         File::new("config", FileFormat::Yaml)
     };
-    let cfg: Cfg = Config::builder()
+    load_config(source)
+}
+
+/// Deserializes and normalizes the config from `source`, see `get_config`
+pub fn load_config(source: impl Source + Send + Sync + 'static) -> anyhow::Result<Arc<Cfg>> {
+    let config = Config::builder()
         .add_source(source)
-        .build()?
-        .try_deserialize()?;
+        .build()?;
+    // unknown keys are ignored, this one used to mean something
+    if config.get::<config::Value>("enforce_https_upstream").is_ok() {
+        tracing::warn!("enforce_https_upstream was removed and is ignored, plain-http upstreams are always refused");
+    }
+    let mut cfg: Cfg = config.try_deserialize()?;
+
+    cfg.host = cfg.host
+        .iter()
+        .map(|host| normalize_host("host", host).map(|host| with_default_port(host, cfg.upstream_port)))
+        .collect::<anyhow::Result<_>>()?;
+    cfg.ws_host = cfg.ws_host
+        .map(|ws_host| normalize_host("ws_host", &ws_host))
+        .transpose()?
+        .map(|ws_host| with_default_port(ws_host, cfg.upstream_port));
+    cfg.shadow_host = cfg.shadow_host
+        .map(|shadow_host| normalize_host("shadow_host", &shadow_host))
+        .transpose()?
+        .map(|shadow_host| with_default_port(shadow_host, cfg.upstream_port));
 
     Ok(cfg.into())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{capture_logs, test_config};
+
+    fn rewrite(strip_prefix: Option<&str>, prefix: Option<&str>) -> PathRewrite {
+        PathRewrite { strip_prefix: strip_prefix.map(str::to_owned), prefix: prefix.map(str::to_owned) }
+    }
+
+    #[test]
+    fn ws_path_defaults_to_the_inbound_path() {
+        assert_eq!(PathRewrite::default().apply("/polyanalyst/eventsSocket"), "/polyanalyst/eventsSocket");
+        assert_eq!(PathRewrite::default().apply("/ws"), "/ws");
+    }
+
+    #[test]
+    fn ws_path_maps_pa6_and_pag_routes() {
+        let pa6 = rewrite(Some("/polyanalyst"), Some("/pa/api/"));
+        assert_eq!(pa6.apply("/polyanalyst/eventsSocket"), "/pa/api/eventsSocket");
+
+        let pag = rewrite(Some("/ws"), Some("/grid/events"));
+        assert_eq!(pag.apply("/ws"), "/grid/events");
+        assert_eq!(pag.apply("/ws/session"), "/grid/events/session");
+    }
+
+    #[test]
+    fn ws_path_stays_absolute() {
+        assert_eq!(rewrite(Some("/ws"), None).apply("/ws"), "/");
+        assert_eq!(rewrite(Some("/ws/"), None).apply("/ws/events"), "/events");
+        assert_eq!(rewrite(Some("/other"), None).apply("/ws"), "/ws");
+    }
+
+    #[test]
+    fn hosts_are_reduced_to_authority() {
+        assert_eq!(normalize_host("host", "backend:5043").unwrap(), "backend:5043");
+        assert_eq!(normalize_host("host", " https://backend:5043 ").unwrap(), "backend:5043");
+        assert_eq!(normalize_host("host", "HTTPS://backend/").unwrap(), "backend");
+        assert_eq!(normalize_host("host", "[::1]:5043").unwrap(), "[::1]:5043");
+    }
+
+    #[test]
+    fn hosts_with_more_than_authority_are_rejected() {
+        assert!(normalize_host("host", "http://backend").is_err());
+        assert!(normalize_host("host", "backend/api").is_err());
+        assert!(normalize_host("host", "user:pass@backend").is_err());
+        assert!(normalize_host("host", "back end").is_err());
+    }
+
+    #[test]
+    fn default_port_only_fills_a_missing_one() {
+        assert_eq!(with_default_port("backend".into(), Some(8443)), "backend:8443");
+        assert_eq!(with_default_port("backend:5043".into(), Some(8443)), "backend:5043");
+        assert_eq!(with_default_port("backend".into(), None), "backend");
+    }
+
+    #[test]
+    fn removed_enforce_https_upstream_is_warned_about() {
+        let upstream = "127.0.0.1:5043".parse().unwrap();
+        let (logs, _subscriber) = capture_logs();
+        test_config(upstream, "");
+        assert!(!logs.text().contains("enforce_https_upstream"));
+        test_config(upstream, "enforce_https_upstream: false");
+        assert!(logs.text().contains("WARN") && logs.text().contains("enforce_https_upstream was removed"));
+    }
+}
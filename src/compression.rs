@@ -0,0 +1,124 @@
+use std::io;
+
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZlibDecoder};
+use axum::{body::Body, extract::Request, response::Response};
+use futures_util::TryStreamExt;
+use http::{header, HeaderMap, HeaderValue};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+pub fn gzip_request_body(req: Request) -> Request {
+    let (mut parts, body) = req.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+
+    let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    let body = Body::from_stream(ReaderStream::new(GzipEncoder::new(reader)));
+
+    Request::from_parts(parts, body)
+}
+
+/// Whether `encoding` is acceptable according to the client's `Accept-Encoding`;
+/// a missing header is treated as identity-only
+pub fn accepts_encoding(accept_encoding: Option<&str>, encoding: &str) -> bool {
+    let Some(accept_encoding) = accept_encoding else {
+        return false;
+    };
+    accept_encoding.split(',').any(|item| {
+        let mut params = item.split(';');
+        let coding = params.next().unwrap_or_default().trim();
+        let rejected = params.any(|param| {
+            param.trim().strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0)
+        });
+        !rejected && (coding == "*" || coding.eq_ignore_ascii_case(encoding))
+    })
+}
+
+/// Decodes a gzip/deflate response body the client didn't ask for,
+/// other encodings are passed through untouched
+pub fn decode_response_body(response: Response, accept_encoding: Option<&str>) -> Response {
+    match content_encoding(response.headers()) {
+        Some(encoding) if !accepts_encoding(accept_encoding, &encoding) => decode_response(response),
+        _ => response,
+    }
+}
+
+/// Decodes a gzip/deflate response body whatever the client accepts, e.g. to rewrite it;
+/// other encodings are passed through untouched
+pub fn decode_response(response: Response) -> Response {
+    let Some(encoding) = content_encoding(response.headers()) else {
+        return response;
+    };
+    if !is_decodable(&encoding) {
+        tracing::debug!("Can't decode {} response body, passing it through", encoding);
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = decode_body(body, &encoding);
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    Response::from_parts(parts, body)
+}
+
+/// Decodes a gzip/deflate request body for upstreams that can't,
+/// other encodings are passed through untouched
+pub fn decode_request_body(req: Request) -> Request {
+    let Some(encoding) = content_encoding(req.headers()) else {
+        return req;
+    };
+    if !is_decodable(&encoding) {
+        tracing::debug!("Can't decode {} request body, passing it through", encoding);
+        return req;
+    }
+
+    let (mut parts, body) = req.into_parts();
+    let body = decode_body(body, &encoding);
+    parts.headers.remove(header::CONTENT_ENCODING);
+    parts.headers.remove(header::CONTENT_LENGTH);
+
+    Request::from_parts(parts, body)
+}
+
+fn content_encoding(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase())
+}
+
+fn is_decodable(encoding: &str) -> bool {
+    matches!(encoding, "gzip" | "x-gzip" | "deflate")
+}
+
+fn decode_body(body: Body, encoding: &str) -> Body {
+    let reader = StreamReader::new(body.into_data_stream().map_err(io::Error::other));
+    if encoding == "deflate" {
+        Body::from_stream(ReaderStream::new(ZlibDecoder::new(reader)))
+    } else {
+        Body::from_stream(ReaderStream::new(GzipDecoder::new(reader)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepted_encodings_match_by_name() {
+        assert!(accepts_encoding(Some("gzip, deflate, br"), "gzip"));
+        assert!(accepts_encoding(Some("deflate, GZIP"), "gzip"));
+        assert!(accepts_encoding(Some("gzip;q=0.5"), "gzip"));
+        assert!(accepts_encoding(Some("*"), "gzip"));
+        assert!(!accepts_encoding(Some("br, deflate"), "gzip"));
+    }
+
+    #[test]
+    fn refused_or_missing_encodings_are_not_accepted() {
+        assert!(!accepts_encoding(None, "gzip"));
+        assert!(!accepts_encoding(Some(""), "gzip"));
+        assert!(!accepts_encoding(Some("gzip;q=0"), "gzip"));
+        assert!(!accepts_encoding(Some("gzip; q=0.0, br"), "gzip"));
+        assert!(!accepts_encoding(Some("*;q=0"), "gzip"));
+    }
+}
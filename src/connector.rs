@@ -0,0 +1,45 @@
+use std::{future::Future, pin::Pin, sync::Arc, task::{Context, Poll}};
+
+use hyper::Uri;
+use hyper_util::client::legacy::connect::HttpConnector;
+use tower::Service;
+
+use crate::state::State;
+
+/// `HttpConnector` that counts every new upstream connection, so pool reuse
+/// can be told apart from connection churn
+#[derive(Clone)]
+pub struct CountingConnector {
+    inner: HttpConnector,
+    state: Arc<State>,
+}
+
+impl CountingConnector {
+    pub fn new(state: Arc<State>) -> Self {
+        let mut inner = HttpConnector::new();
+        // the scheme is checked by the HTTPS connector on top of this one
+        inner.enforce_http(false);
+        Self { inner, state }
+    }
+}
+
+impl Service<Uri> for CountingConnector {
+    type Response = <HttpConnector as Service<Uri>>::Response;
+    type Error = <HttpConnector as Service<Uri>>::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.inner.call(uri);
+        let state = self.state.clone();
+        Box::pin(async move {
+            let stream = connecting.await?;
+            let total = state.upstream_connection_opened();
+            tracing::debug!("Opened upstream connection, {} in total", total);
+            Ok(stream)
+        })
+    }
+}
@@ -0,0 +1,135 @@
+use std::net::{IpAddr, SocketAddr};
+
+use http::{header, HeaderMap, HeaderName, HeaderValue};
+
+use crate::cfg::{UserAgent, UserAgentMode};
+
+const SENSITIVE_HEADERS: [HeaderName; 4] = [
+    header::AUTHORIZATION,
+    header::PROXY_AUTHORIZATION,
+    header::COOKIE,
+    header::SET_COOKIE,
+];
+
+/// Removes every header matching one of `patterns`, compared case-insensitively;
+/// a trailing `*` matches any header with that prefix
+pub fn strip_headers(headers: &mut HeaderMap, patterns: &[String]) {
+    if patterns.is_empty() {
+        return;
+    }
+
+    let matches = |name: &HeaderName| patterns.iter().any(|pattern| {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => name.as_str().get(..prefix.len())
+                .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+            None => name.as_str().eq_ignore_ascii_case(pattern),
+        }
+    });
+    let names: Vec<HeaderName> = headers.keys().filter(|name| matches(name)).cloned().collect();
+    for name in names {
+        headers.remove(name);
+    }
+}
+
+pub fn apply_user_agent(headers: &mut HeaderMap, user_agent: &UserAgent) {
+    let value = match (&user_agent.mode, headers.get(header::USER_AGENT)) {
+        (UserAgentMode::Append, Some(client)) => {
+            let mut value = client.as_bytes().to_vec();
+            value.push(b' ');
+            value.extend_from_slice(user_agent.value.as_bytes());
+            // both halves are valid header values, so is their space-joined concatenation
+            HeaderValue::from_bytes(&value).unwrap_or_else(|_| user_agent.value.clone())
+        },
+        _ => user_agent.value.clone(),
+    };
+    headers.insert(header::USER_AGENT, value);
+}
+
+/// RFC 7239 value: a token when possible, a quoted string otherwise
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty() && value.bytes().all(|byte| {
+        byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+    });
+    if is_token {
+        value.to_owned()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Adds a `Forwarded: for=..;proto=http;host=..` element after any the client sent
+pub fn append_forwarded(headers: &mut HeaderMap, client: Option<SocketAddr>, host: Option<&str>) {
+    let mut pairs = Vec::new();
+    match client.map(|client| client.ip()) {
+        Some(IpAddr::V4(ip)) => pairs.push(format!("for={}", ip)),
+        Some(IpAddr::V6(ip)) => pairs.push(format!("for={}", forwarded_value(&format!("[{}]", ip)))),
+        None => pairs.push("for=unknown".to_owned()),
+    }
+    // the proxy listener is plain HTTP
+    pairs.push("proto=http".to_owned());
+    if let Some(host) = host {
+        pairs.push(format!("host={}", forwarded_value(host)));
+    }
+    let element = pairs.join(";");
+
+    // the client may have sent several field lines, they're one list
+    let mut value = Vec::new();
+    for existing in headers.get_all(header::FORWARDED) {
+        value.extend_from_slice(existing.as_bytes());
+        value.extend_from_slice(b", ");
+    }
+    value.extend_from_slice(element.as_bytes());
+    match HeaderValue::from_bytes(&value) {
+        Ok(value) => {
+            headers.insert(header::FORWARDED, value);
+        },
+        Err(e) => tracing::warn!("Not sending invalid Forwarded header {:?}: {}", element, e),
+    }
+}
+
+pub fn is_sensitive(name: &HeaderName) -> bool {
+    SENSITIVE_HEADERS.contains(name)
+}
+
+/// Logs every header at DEBUG, one line each; credentials are masked unless `sensitive` is set
+pub fn log_headers(direction: &str, headers: &HeaderMap, sensitive: bool) {
+    for (name, value) in headers {
+        if !sensitive && is_sensitive(name) {
+            tracing::debug!("{} {}: <redacted>", direction, name);
+        } else {
+            tracing::debug!("{} {}: {}", direction, name, String::from_utf8_lossy(value.as_bytes()));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded(headers: &HeaderMap) -> Vec<&str> {
+        headers.get_all(header::FORWARDED).iter().map(|value| value.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn forwarded_element_is_added() {
+        let mut headers = HeaderMap::new();
+        append_forwarded(&mut headers, Some("192.0.2.1:5000".parse().unwrap()), Some("pa.example.com"));
+        assert_eq!(forwarded(&headers), ["for=192.0.2.1;proto=http;host=pa.example.com"]);
+
+        let mut headers = HeaderMap::new();
+        append_forwarded(&mut headers, Some("[2001:db8::1]:5000".parse().unwrap()), None);
+        assert_eq!(forwarded(&headers), ["for=\"[2001:db8::1]\";proto=http"]);
+    }
+
+    #[test]
+    fn forwarded_keeps_every_line_the_client_sent() {
+        let mut headers = HeaderMap::new();
+        headers.append(header::FORWARDED, HeaderValue::from_static("for=198.51.100.1"));
+        headers.append(header::FORWARDED, HeaderValue::from_static("for=198.51.100.2, for=198.51.100.3"));
+        append_forwarded(&mut headers, None, None);
+        assert_eq!(
+            forwarded(&headers),
+            ["for=198.51.100.1, for=198.51.100.2, for=198.51.100.3, for=unknown;proto=http"]
+        );
+    }
+}
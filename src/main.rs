@@ -1,18 +1,40 @@
+mod access;
+mod admin;
+mod body;
 mod cfg;
+mod compression;
+mod connector;
+mod headers;
 mod ws;
 mod tls;
+mod pages;
+mod probe;
+mod retry;
+mod rewrite;
+mod proxy_protocol;
 mod router;
+mod server;
+mod shadow;
 mod shutdown;
+mod state;
+#[cfg(test)]
+mod testing;
+mod timeout;
 
-use std::{net::SocketAddr, str::FromStr};
+use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
 use tokio::net::TcpListener;
 use tracing_subscriber::{prelude::*, registry::Registry, fmt};
 use tracing::{level_filters::LevelFilter, Level};
 use clap::Parser;
 
+use admin::get_admin_router;
 use router::get_router;
+use server::ServeOptions;
 use cfg::get_config;
-use shutdown::shutdown_signal;
+use probe::probe_upstream;
+use shutdown::{dump_stats_on_signal, shutdown_signal, ShutdownSignal};
+use state::State;
+use tls::{build_client_config, build_https_client};
 
 #[derive(Parser)]
 struct Args {
@@ -20,47 +42,141 @@ struct Args {
     config: Option<std::path::PathBuf>,
     #[arg(long)]
     loglevel: Option<String>,
+    /// Skip upstream TLS certificate verification, same as insecure_tls: true
+    #[arg(long)]
+    insecure: bool,
 }
 
-async fn main_impl(args: Args) -> anyhow::Result<()> {
+async fn main_impl(args: Args) -> anyhow::Result<Option<ShutdownSignal>> {
     tracing::info!("Logging subsystem initialized correctly");
 
     let cfg = get_config(args.config)?;
-    let router = get_router(cfg.clone())?;
+    let insecure = cfg.insecure_tls || args.insecure;
+    if insecure {
+        tracing::warn!(
+            "Upstream TLS certificate verification is DISABLED, a man-in-the-middle would go unnoticed; \
+            don't run this in production"
+        );
+    }
+
+    let addr = SocketAddr::new(cfg.bind, cfg.port);
+    let upstreams = |scheme: &str, hosts: &[String]| hosts
+        .iter()
+        .map(|host| format!("{}://{}", scheme, host))
+        .collect::<Vec<_>>()
+        .join(", ");
+    tracing::info!(
+        "Instance {} proxying http://{} to {} (WebSocket {}), {} mode, TLS verification {}, \
+        static {}, help {}, features: [{}]",
+        cfg.instance_name,
+        addr,
+        upstreams("https", &cfg.host),
+        match &cfg.ws_host {
+            Some(ws_host) => upstreams("wss", std::slice::from_ref(ws_host)),
+            None => upstreams("wss", &cfg.host),
+        },
+        if cfg.pagrid { "pagrid" } else { "pa6" },
+        if insecure { "off" } else { "on" },
+        cfg.sourcedata,
+        cfg.help,
+        cfg.enabled_features().join(", ")
+    );
+
+    let state = Arc::new(State::default());
+    let tls_config = build_client_config(&cfg, insecure)?;
+    let client = build_https_client(&cfg, tls_config.clone(), state.clone())?;
+    let router = get_router(cfg.clone(), client.clone(), Arc::new(tls_config), state.clone())?;
+
+    state.set_ready(!cfg.upstream_probe.block_readiness);
+    for host in &cfg.host {
+        tokio::spawn(probe_upstream(client.clone(), cfg.clone(), state.clone(), host.clone()));
+    }
+    tokio::spawn(dump_stats_on_signal(cfg.instance_name.clone(), state.clone()));
+
+    let listener = server::bind_with_retries(
+        addr,
+        cfg.listen_backlog,
+        cfg.bind_retries,
+        Duration::from_millis(cfg.bind_retry_delay_ms)
+    ).await?;
 
-    let addr = SocketAddr::from(([127, 0, 0, 1], cfg.port));
-    let listener = TcpListener::bind(addr).await?;
+    let (signal_tx, signal_rx) = tokio::sync::watch::channel(None);
 
-    tracing::info!("Starting proxy server at http://127.0.0.1:{}", cfg.port);
-    if let Err(e) = axum::serve(listener, router.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-    {
-        return Err(anyhow::anyhow!(e));
+    if let Some(shutdown_timeout_secs) = cfg.shutdown_timeout_secs {
+        let mut signal_rx = signal_rx.clone();
+        let state = state.clone();
+        tokio::spawn(async move {
+            if signal_rx.wait_for(Option::is_some).await.is_err() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs(shutdown_timeout_secs)).await;
+            tracing::warn!(
+                "Graceful shutdown did not complete in {}s with {} connections still open ({} WebSockets), \
+                forcing exit",
+                shutdown_timeout_secs, state.open_connections(), state.open_websockets()
+            );
+            std::process::exit(1);
+        });
     }
-    Ok(())
+
+    if let Some(admin_port) = cfg.admin_port {
+        let addr = SocketAddr::from(([127, 0, 0, 1], admin_port));
+        let admin_listener = TcpListener::bind(addr).await?;
+        let admin_router = get_admin_router(cfg.clone(), state.clone());
+        let mut signal_rx = signal_rx.clone();
+
+        tracing::info!("Starting admin server at http://{}", addr);
+        tokio::spawn(async move {
+            let signal = async move {
+                let _ = signal_rx.wait_for(Option::is_some).await;
+            };
+            if let Err(e) = server::serve(admin_listener, admin_router, ServeOptions::default(), signal).await {
+                tracing::error!("Admin server error: {}", e);
+            }
+        });
+    }
+
+    tracing::info!("Starting proxy server at http://{}", addr);
+    let options = ServeOptions {
+        state: Some(state),
+        ..ServeOptions::from(&*cfg)
+    };
+    server::serve(listener, router, options, async move {
+        signal_tx.send_replace(Some(shutdown_signal().await));
+    }).await?;
+    let signal = *signal_rx.borrow();
+    Ok(signal)
 }
 
 fn main() {
     let args = Args::parse();
 
+    let level = args.loglevel
+        .as_deref()
+        .and_then(|loglevel| Level::from_str(loglevel).ok())
+        .map(LevelFilter::from_level)
+        .unwrap_or(LevelFilter::INFO);
     let fmt_layer = fmt::layer()
         .with_target(false)
-        .with_filter(args.loglevel
-            .clone()
-            .and_then(|loglevel| Level::from_str(&loglevel).ok())
-            .map(|loglevel| loglevel.into())
-            .unwrap_or(LevelFilter::INFO)
-        );
+        .with_filter(level);
 
     Registry::default()
         .with(fmt_layer)
         .try_init()
         .expect("Could not initialize logging subsystem");
 
+    match &args.loglevel {
+        Some(loglevel) if Level::from_str(loglevel).is_err() => {
+            tracing::warn!("Unknown log level {:?}, falling back to {}", loglevel, level);
+        },
+        _ => tracing::info!("Log level is {}", level),
+    }
+
     let rt = tokio::runtime::Runtime::new().expect("Could not initialize Tokio runtime");
-    if let Err(e) = rt.block_on(main_impl(args)) {
-        tracing::error!("{}", e);
+    match rt.block_on(main_impl(args)) {
+        Ok(Some(signal)) => std::process::exit(signal.exit_code()),
+        Ok(None) => {},
+        Err(e) => tracing::error!("{}", e),
     }
 }
 
@@ -0,0 +1,56 @@
+use anyhow::Context;
+use axum::response::{Html, IntoResponse, Response};
+use bytes::Bytes;
+use http::{header, HeaderMap, StatusCode};
+
+use crate::cfg::Cfg;
+
+/// HTML pages served to browsers instead of bare upstream statuses,
+/// read once at startup
+#[derive(Clone, Default)]
+pub struct Pages {
+    maintenance: Option<Bytes>,
+    error: Option<Bytes>,
+}
+
+impl Pages {
+    pub fn load(cfg: &Cfg) -> anyhow::Result<Self> {
+        let maintenance = read_page(cfg.maintenance_page.as_ref(), "maintenance")?;
+        let error = read_page(cfg.error_page.as_ref(), "error")?;
+
+        Ok(Self { maintenance, error })
+    }
+
+    /// Replaces an upstream 503 with the maintenance page, keeping `Retry-After`
+    pub fn maintenance(&self, upstream_headers: &HeaderMap) -> Option<Response> {
+        let page = self.maintenance.clone()?;
+        let mut response = (StatusCode::SERVICE_UNAVAILABLE, Html(page)).into_response();
+        if let Some(retry_after) = upstream_headers.get(header::RETRY_AFTER) {
+            response.headers_mut().insert(header::RETRY_AFTER, retry_after.clone());
+        }
+        Some(response)
+    }
+
+    /// Page for a proxy failure, served with the failure's own status
+    pub fn error(&self, status: StatusCode) -> Option<Response> {
+        let page = self.error.clone()?;
+        Some((status, Html(page)).into_response())
+    }
+}
+
+fn read_page(path: Option<&String>, kind: &str) -> anyhow::Result<Option<Bytes>> {
+    path
+        .map(|path| std::fs::read(path)
+            .with_context(|| format!("Could not read {} page {}", kind, path))
+        )
+        .transpose()
+        .map(|page| page.map(Bytes::from))
+}
+
+pub fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .any(|value| value.contains("text/html"))
+}
@@ -0,0 +1,52 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::body::Body;
+use http::{header, Request};
+
+use crate::{cfg::Cfg, state::State, tls::HTTPSClient};
+
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Checks once at startup that upstream `host` is healthy, i.e. answers the probe with
+/// a 2xx/3xx in time; when the probe gates readiness it keeps retrying until then.
+/// With several upstream hosts the first healthy one makes the proxy ready
+pub async fn probe_upstream(client: HTTPSClient, cfg: Arc<Cfg>, state: Arc<State>, host: String) {
+    let probe = &cfg.upstream_probe;
+    let method = probe.method.to_ascii_uppercase();
+    let uri = format!("https://{}{}", host, probe.path);
+    let timeout = Duration::from_millis(probe.timeout_ms);
+
+    loop {
+        let request = Request::builder()
+            .method(method.as_str())
+            .uri(&uri)
+            .header(header::HOST, &host)
+            .body(Body::empty());
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                tracing::error!("Can't probe upstream with {} {}: {}", method, uri, e);
+                return;
+            },
+        };
+
+        let failure = match tokio::time::timeout(timeout, client.request(request)).await {
+            Ok(Ok(response)) if response.status().is_success() || response.status().is_redirection() => {
+                tracing::info!("Upstream probe {} {} answered {}, upstream is up", method, uri, response.status());
+                state.set_ready(true);
+                return;
+            },
+            Ok(Ok(response)) => format!("answered {}", response.status()),
+            Ok(Err(e)) => format!("failed: {:?}", e),
+            Err(_) => format!("got no answer within {:?}", timeout),
+        };
+
+        // another host may have made the proxy ready in the meantime
+        if !probe.block_readiness || state.ready() {
+            tracing::warn!("Upstream probe {} {} {}", method, uri, failure);
+            return;
+        }
+        tracing::warn!("Upstream probe {} {} {}, not ready yet", method, uri, failure);
+        tokio::time::sleep(RETRY_INTERVAL).await;
+    }
+}
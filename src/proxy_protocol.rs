@@ -0,0 +1,144 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY";
+const V1_MAX_LEN: u64 = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a PROXY protocol v1 or v2 header from the start of a connection.
+/// Returns the original client address, or `None` when the balancer reports
+/// no address (`UNKNOWN`/`LOCAL`, e.g. its own health checks).
+pub async fn read_header<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 5];
+    reader.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        read_v1(reader).await
+    } else if prefix == V2_SIGNATURE[..5] {
+        read_v2(reader).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut line = Vec::new();
+    reader.take(V1_MAX_LEN - V1_PREFIX.len() as u64).read_until(b'\n', &mut line).await?;
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or_else(|| invalid("unterminated PROXY v1 header"))?;
+    let line = std::str::from_utf8(line).map_err(|_| invalid("non-ASCII PROXY v1 header"))?;
+
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["", "UNKNOWN", ..] => Ok(None),
+        ["", "TCP4" | "TCP6", src, _dst, sport, _dport] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad PROXY v1 source address"))?;
+            let port: u16 = sport.parse().map_err(|_| invalid("bad PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncBufRead + Unpin>(reader: &mut R) -> io::Result<Option<SocketAddr>> {
+    // rest of the signature, version/command, family/transport, length
+    let mut header = [0u8; 11];
+    reader.read_exact(&mut header).await?;
+    if header[..7] != V2_SIGNATURE[5..] {
+        return Err(invalid("bad PROXY v2 signature"));
+    }
+
+    let version_command = header[7];
+    let family = header[8];
+    let len = u16::from_be_bytes([header[9], header[10]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+
+    // LOCAL command: connection made by the balancer itself
+    if version_command & 0x0f == 0 {
+        return Ok(None);
+    }
+
+    let addr = match family >> 4 {
+        // AF_INET
+        1 if len >= 12 => {
+            let ip: [u8; 4] = payload[0..4].try_into().unwrap();
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Some(SocketAddr::new(Ipv4Addr::from(ip).into(), port))
+        },
+        // AF_INET6
+        2 if len >= 36 => {
+            let ip: [u8; 16] = payload[0..16].try_into().unwrap();
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Some(SocketAddr::new(Ipv6Addr::from(ip).into(), port))
+        },
+        1 | 2 => return Err(invalid("truncated PROXY v2 address block")),
+        // AF_UNSPEC / AF_UNIX carry no usable client address
+        _ => None,
+    };
+    Ok(addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+        read_header(&mut &header[..]).await
+    }
+
+    fn v2(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((addresses.len() as u16).to_be_bytes());
+        header.extend(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_reports_the_source_address() {
+        let addr = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n").await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+
+        let addr = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_reports_the_source_address() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        assert_eq!(read(&v2(1, 0x11, &ipv4)).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+
+        let mut ipv6 = [0u8; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&56324u16.to_be_bytes());
+        assert_eq!(read(&v2(1, 0x21, &ipv6)).await.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        // LOCAL, the balancer's own health check
+        assert_eq!(read(&v2(0, 0x11, &ipv4)).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn malformed_headers_are_rejected() {
+        assert!(read(b"GET / HTTP/1.1\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 not-an-ip 198.51.100.1 56324 443\r\n").await.is_err());
+        assert!(read(format!("PROXY TCP4 {}\r\n", "1".repeat(120)).as_bytes()).await.is_err());
+        assert!(read(&v2(1, 0x11, &[192, 0, 2, 1])).await.is_err());
+    }
+}
@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use axum::extract::Request;
+use bytes::Bytes;
+use http::{request::Parts, Method};
+use hyper::{body::Incoming, Response};
+
+use crate::tls::HTTPSClient;
+
+/// Wait before the first retry, doubled for each one after it up to `MAX_RETRY_BACKOFF`
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Sends `req`, then resends its `replay` copy up to `retries` times while the upstream can't be
+/// reached; an upstream that answers at all, 5xx included, isn't retried, see `is_retryable`
+pub async fn send_with_retries(
+    client: &HTTPSClient,
+    req: Request,
+    replay: Option<(Parts, Bytes)>,
+    retries: u32,
+) -> Result<Response<Incoming>, hyper_util::client::legacy::Error> {
+    let mut result = client.request(req).await;
+    let Some((parts, body)) = replay else {
+        return result;
+    };
+
+    for attempt in 1..=retries {
+        let e = match result {
+            Ok(response) => return Ok(response),
+            Err(e) if is_retryable(&parts.method, &e) => e,
+            Err(e) => return Err(e),
+        };
+        let backoff = RETRY_BACKOFF
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(MAX_RETRY_BACKOFF);
+        tracing::debug!(
+            "Retrying {} {} in {:?}, attempt {} of {}: {}", parts.method, parts.uri, backoff, attempt, retries, e
+        );
+        tokio::time::sleep(backoff).await;
+        result = client.request(Request::from_parts(parts.clone(), body.clone().into())).await;
+    }
+    result
+}
+
+/// GETs and HEADs are resent after any failure, other methods only when no connection could be
+/// opened: once a request went out, the upstream may have acted on it already
+fn is_retryable(method: &Method, e: &hyper_util::client::legacy::Error) -> bool {
+    e.is_connect() || matches!(*method, Method::GET | Method::HEAD)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::Ordering, Arc}, time::Instant};
+
+    use super::*;
+    use crate::{
+        testing::{mock_upstream_hanging_up, test_config},
+        tls::{build_client_config, build_https_client},
+    };
+
+    async fn send_to_hanging_upstream(method: Method) -> usize {
+        let (upstream, requests) = mock_upstream_hanging_up().await;
+        let cfg = test_config(upstream, "");
+        let client = build_https_client(&cfg, build_client_config(&cfg, true).unwrap(), Arc::default()).unwrap();
+        let req = Request::builder()
+            .method(method)
+            .uri(format!("https://{}/api", upstream))
+            .body(Bytes::from_static(b"payload").into())
+            .unwrap();
+        let (parts, body) = req.into_parts();
+        let replay = (parts.clone(), Bytes::from_static(b"payload"));
+
+        let result = send_with_retries(&client, Request::from_parts(parts, body), Some(replay), 2).await;
+        assert!(result.is_err());
+        requests.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn gets_are_resent_after_the_upstream_hangs_up() {
+        assert_eq!(send_to_hanging_upstream(Method::GET).await, 3);
+    }
+
+    #[tokio::test]
+    async fn posts_the_upstream_received_are_not_resent() {
+        assert_eq!(send_to_hanging_upstream(Method::POST).await, 1);
+    }
+
+    #[tokio::test]
+    async fn posts_are_resent_while_the_upstream_cant_be_reached() {
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let cfg = test_config(closed, "");
+        let client = build_https_client(&cfg, build_client_config(&cfg, true).unwrap(), Arc::default()).unwrap();
+        let req = Request::post(format!("https://{}/api", closed)).body(Bytes::new().into()).unwrap();
+        let (parts, body) = req.into_parts();
+        let replay = (parts.clone(), Bytes::new());
+
+        let started = Instant::now();
+        let e = send_with_retries(&client, Request::from_parts(parts, body), Some(replay), 2).await.unwrap_err();
+        assert!(e.is_connect());
+        // both retries waited out their backoff
+        assert!(started.elapsed() >= RETRY_BACKOFF * 3);
+    }
+}
@@ -0,0 +1,69 @@
+use axum::{body::Body, response::Response};
+use bytes::Bytes;
+use futures_util::StreamExt;
+use http::{header, HeaderValue, StatusCode};
+
+use crate::{cfg::BodyRewrite, compression::decode_response};
+
+/// Only markup, stylesheets and scripts carry URLs worth rewriting
+const REWRITABLE_TYPES: [&str; 5] = [
+    "text/html",
+    "text/css",
+    "text/javascript",
+    "application/javascript",
+    "application/x-javascript",
+];
+
+fn is_rewritable(response: &Response) -> bool {
+    if response.status() != StatusCode::OK {
+        return false;
+    }
+    let Some(content_type) = response.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+    else {
+        return false;
+    };
+    let mime = content_type.split(';').next().unwrap_or_default().trim();
+    REWRITABLE_TYPES.iter().any(|rewritable| mime.eq_ignore_ascii_case(rewritable))
+}
+
+/// Applies `rewrite` to text responses read whole into memory, decoding gzip/deflate
+/// ones first; other encodings and bodies not UTF-8 or over `max_body_bytes` are relayed
+/// untouched, apart from the decoding
+pub async fn rewrite_response_body(response: Response, rewrite: &BodyRewrite) -> anyhow::Result<Response> {
+    if !is_rewritable(&response) {
+        return Ok(response);
+    }
+    let response = decode_response(response);
+    if response.headers().contains_key(header::CONTENT_ENCODING) {
+        return Ok(response);
+    }
+    let (mut parts, body) = response.into_parts();
+
+    let mut stream = body.into_data_stream();
+    let mut chunks = Vec::new();
+    let mut total = 0;
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        total += chunk.len();
+        chunks.push(chunk);
+        if total > rewrite.max_body_bytes {
+            tracing::debug!("Response body over {} bytes, not rewriting it", rewrite.max_body_bytes);
+            let read = futures_util::stream::iter(chunks.into_iter().map(Ok));
+            return Ok(Response::from_parts(parts, Body::from_stream(read.chain(stream))));
+        }
+    }
+
+    let body = chunks.concat();
+    let body = match String::from_utf8(body) {
+        Ok(text) => Bytes::from(rewrite.apply(text)),
+        Err(e) => Bytes::from(e.into_bytes()),
+    };
+    // the new body goes out in one piece, and the upstream's validator no longer describes it
+    parts.headers.remove(header::TRANSFER_ENCODING);
+    parts.headers.remove(header::ETAG);
+    parts.headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
@@ -1,33 +1,98 @@
-use std::{sync::Arc, path::PathBuf};
+use std::{net::SocketAddr, sync::Arc, path::{Path, PathBuf}, time::{Duration, Instant}};
 
 use anyhow::Context;
 use axum::{
     Router,
-    routing::get,
-    extract::{ws::WebSocket, WebSocketUpgrade, Extension, Request},
-    response::IntoResponse
+    routing::{any, get},
+    routing::{any_service, MethodRouter},
+    extract::{ws::{close_code, rejection::WebSocketUpgradeRejection, CloseFrame, Message, WebSocket}, WebSocketUpgrade, ConnectInfo, Extension, OriginalUri, Request},
+    handler::HandlerWithoutStateExt,
+    middleware,
+    response::IntoResponse,
+    Json,
 };
 use futures_util::{StreamExt, SinkExt};
-use hyper::{StatusCode, Uri, body::Incoming};
-use tokio_tungstenite::{connect_async_tls_with_config, tungstenite::handshake::client::generate_key};
-use tower_http::{services::ServeDir, trace::TraceLayer};
+use http_body_util::LengthLimitError;
+use hyper::{body::Body as _, StatusCode, Uri};
+use serde_derive::Serialize;
+use tokio::sync::{mpsc::{self, error::TrySendError}, Notify};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async_tls_with_config,
+    tungstenite::handshake::client::generate_key,
+    MaybeTlsStream,
+    WebSocketStream,
+};
+use tower_http::{
+    services::{ServeDir, ServeFile},
+    set_header::SetResponseHeaderLayer,
+    classify::{ServerErrorsAsFailures, SharedClassifier},
+    trace::{DefaultOnBodyChunk, DefaultOnEos, DefaultOnFailure, DefaultOnRequest, DefaultOnResponse, TraceLayer},
+    LatencyUnit,
+};
+use tracing::Instrument;
 
 use crate::{
-    cfg::Cfg,
-    tls::{HTTPSClient, build_https_client, build_client_config},
-    ws::{axum_to_tungstein, tungstein_to_axum},
+    access,
+    body::count_bytes,
+    cfg::{Cfg, Direction, Favicon, StaticFallback},
+    compression::{decode_request_body, decode_response_body, gzip_request_body},
+    headers::{append_forwarded, apply_user_agent, log_headers, strip_headers},
+    pages::{Pages, accepts_html},
+    retry::send_with_retries,
+    rewrite::rewrite_response_body,
+    server::Shutdown,
+    shadow::{send_shadow, shadow_request},
+    state::{State, WebSocketEntry, WebSocketGuard, WsRejection},
+    timeout::request_timeout,
+    tls::HTTPSClient,
+    ws::{axum_to_tungstein, payload_len, replace_text, tungstein_to_axum},
 };
 
+/// Methods the proxy fallback forwards, reported to OPTIONS when answered locally
+const PROXY_METHODS: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+
+fn allow_response(allow: &'static str) -> axum::response::Response {
+    (StatusCode::NO_CONTENT, [(http::header::ALLOW, allow)]).into_response()
+}
+
+/// Answers OPTIONS on `route` itself with `allow` when `answer_options` is on
+fn with_local_options(route: MethodRouter, cfg: &Cfg, allow: &'static str) -> MethodRouter {
+    if cfg.answer_options {
+        route.options(move || async move { allow_response(allow) })
+    } else {
+        route
+    }
+}
+
 async fn handler(
     Extension(client): Extension<HTTPSClient>,
     Extension(cfg): Extension<Arc<Cfg>>,
+    Extension(pages): Extension<Pages>,
+    Extension(state): Extension<Arc<State>>,
     req: Request
 ) -> std::result::Result<axum::response::Response, StatusCode> {
     async fn handler_impl(
         client: HTTPSClient,
         cfg: Arc<Cfg>,
+        pages: Pages,
+        state: Arc<State>,
         mut req: Request
-    ) -> anyhow::Result<hyper::Response<Incoming>> {
+    ) -> anyhow::Result<axum::response::Response> {
+        // static mounts strip their prefix before falling back to the proxy
+        if let Some(OriginalUri(uri)) = req.extensions().get::<OriginalUri>().cloned() {
+            *req.uri_mut() = uri;
+        }
+
+        let started = Instant::now();
+        let method = req.method().clone();
+        let html_client = accepts_html(req.headers());
+        let accept_encoding = req.headers()
+            .get(http::header::ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        let has_body = req.method() != http::Method::HEAD;
+
         let path = req.uri().path();
         let path_query = req
             .uri()
@@ -35,82 +100,428 @@ async fn handler(
             .map(|v| v.as_str())
             .unwrap_or(path);
     
-        let uri = format!("https://{}{}", cfg.host, path_query);
+        let host = state.next_upstream(&cfg.host);
+        let uri = format!("https://{}{}", host, path_query);
         tracing::info!("{} {}", req.method(), uri);
 
-        *req.uri_mut() = Uri::try_from(uri)?;
+        let uri = Uri::try_from(uri)?;
+        *req.uri_mut() = uri.clone();
+        // inbound h2c requests still go upstream over HTTP/1.1
+        *req.version_mut() = http::Version::HTTP_11;
+
+        if cfg.forwarded_header {
+            let client = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| *addr);
+            let host = req.headers()
+                .get(http::header::HOST)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_owned);
+            append_forwarded(req.headers_mut(), client, host.as_deref());
+        }
 
         let headers = req.headers_mut();
         if headers.contains_key(http::header::HOST) {
-            headers.insert(http::header::HOST, cfg.host.parse()?);
+            headers.insert(http::header::HOST, host.parse()?);
+        }
+        if let Some(user_agent) = &cfg.user_agent {
+            apply_user_agent(headers, user_agent);
+        }
+        // hyper answers the client's 100-continue once the body is read, and the upstream
+        // request goes out with its body right away, so the upstream has nothing to wait for
+        headers.remove(http::header::EXPECT);
+
+        // only GETs, so the shadow upstream never sees a write twice
+        let shadow = match &cfg.shadow_host {
+            Some(shadow_host) if req.method() == http::Method::GET => Some(shadow_request(&req, shadow_host)?),
+            _ => None,
+        };
+
+        if cfg.decompress_requests {
+            req = decode_request_body(req);
+        }
+
+        let gzip_body = !matches!(*req.method(), http::Method::GET | http::Method::HEAD)
+            && !req.headers().contains_key(http::header::CONTENT_ENCODING)
+            && cfg.gzip_request_paths.iter().any(|prefix| req.uri().path().starts_with(prefix));
+        if gzip_body {
+            req = gzip_request_body(req);
         }
         
-        let response = client.request(req).await?;
-        Ok(response)
+        // a buffered body is counted right away, however many attempts it takes to send it
+        let buffered = if cfg.buffer_request_body {
+            let (parts, body) = req.into_parts();
+            let body = match axum::body::to_bytes(body, cfg.max_request_body_bytes).await {
+                Ok(body) => body,
+                Err(e) if std::error::Error::source(&e).is_some_and(|e| e.is::<LengthLimitError>()) => {
+                    tracing::warn!(
+                        "Rejecting {} {}: body exceeds max_request_body_bytes of {}",
+                        method, uri, cfg.max_request_body_bytes
+                    );
+                    return Ok((StatusCode::PAYLOAD_TOO_LARGE, "Request body too large").into_response());
+                }
+                Err(e) => anyhow::bail!("Could not read request body for {}: {}", uri, e),
+            };
+            state.record_request_size(body.len() as u64);
+            state.update_sent(uri.path(), body.len() as u64);
+            req = Request::from_parts(parts, body.clone().into());
+            Some(body)
+        } else {
+            None
+        };
+
+        // buffered bodies and bodiless GETs and HEADs can be sent again as they are
+        let replayable = buffered.is_some()
+            || (matches!(*req.method(), http::Method::GET | http::Method::HEAD) && req.body().is_end_stream());
+        let replay = if cfg.retries > 0 && replayable {
+            let (parts, body) = req.into_parts();
+            let replay = (parts.clone(), buffered.clone().unwrap_or_default());
+            req = Request::from_parts(parts, body);
+            Some(replay)
+        } else {
+            None
+        };
+
+        let req = if buffered.is_none() {
+            let request_state = state.clone();
+            let request_path = uri.path().to_owned();
+            req.map(|body| count_bytes(body, move |size| {
+                request_state.record_request_size(size);
+                request_state.update_sent(&request_path, size);
+            }))
+        } else {
+            req
+        };
+
+        if cfg.log_headers {
+            log_headers(">", req.headers(), cfg.log_sensitive);
+        }
+        let send = send_with_retries(&client, req, replay, cfg.retries);
+        let mut response = match cfg.timeout_secs {
+            0 => send.await?,
+            // the Elapsed error is answered 504 by classify_error
+            secs => tokio::time::timeout(Duration::from_secs(secs), send)
+                .await
+                .with_context(|| format!("No answer to {} within {}s", uri, secs))??,
+        };
+        state.record_response(uri.path(), response.status().as_u16());
+        if let Some(shadow) = shadow {
+            match state.shadow_slot() {
+                Some(slot) => {
+                    tokio::spawn(send_shadow(client, shadow, response.status(), cfg.timeout_secs, slot));
+                }
+                None => tracing::debug!("Shadow GET {} dropped, too many in flight", shadow.uri()),
+            }
+        }
+        if cfg.log_headers {
+            log_headers("<", response.headers(), cfg.log_sensitive);
+        }
+        strip_headers(response.headers_mut(), &cfg.strip_response_headers);
+        if let Some(&status) = cfg.status_rewrites.get(&response.status()) {
+            tracing::info!("Rewriting upstream status {} to {} for {}", response.status(), status, uri);
+            *response.status_mut() = status;
+        }
+        if response.status() == StatusCode::SERVICE_UNAVAILABLE && html_client {
+            if let Some(page) = pages.maintenance(response.headers()) {
+                return Ok(page);
+            }
+        }
+
+        let has_body = has_body
+            && !matches!(response.status(), StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED);
+        let mut response = response.into_response();
+        let buffer = cfg.buffer_threshold_bytes
+            .zip(response.body().size_hint().exact())
+            .is_some_and(|(threshold, len)| len < threshold);
+        if buffer {
+            let (parts, body) = response.into_parts();
+            let body = axum::body::to_bytes(body, usize::MAX).await?;
+            response = axum::response::Response::from_parts(parts, body.into());
+        }
+
+        let response = if cfg.decompress_responses && has_body {
+            decode_response_body(response, accept_encoding.as_deref())
+        } else {
+            response
+        };
+        let response = match &cfg.body_rewrite {
+            Some(rewrite) if has_body => rewrite_response_body(response, rewrite).await?,
+            _ => response,
+        };
+        let status = response.status();
+        let log_responses = cfg.log_responses;
+        Ok(response.map(|body| count_bytes(body, move |size| {
+            state.record_response_size(size);
+            state.update_received(uri.path(), size);
+            if log_responses {
+                tracing::info!(
+                    "{} {} {} {} bytes in {}ms",
+                    method, uri.path(), status.as_u16(), size, started.elapsed().as_millis()
+                );
+            }
+        })))
     }
 
-    match handler_impl(client, cfg, req).await {
-        Ok(response) => Ok(response.into_response()),
+    if state.maintenance() {
+        if accepts_html(req.headers()) {
+            if let Some(page) = pages.maintenance(&http::HeaderMap::new()) {
+                return Ok(page);
+            }
+        }
+        return Ok((StatusCode::SERVICE_UNAVAILABLE, "Service is under maintenance").into_response());
+    }
+
+    let original_path = req.extensions()
+        .get::<OriginalUri>()
+        .map(|OriginalUri(uri)| uri.path())
+        .unwrap_or(req.uri().path());
+    if state.is_route_disabled(original_path) {
+        return Ok((StatusCode::SERVICE_UNAVAILABLE, "Route is disabled").into_response());
+    }
+
+    check_header_limits(&req, &cfg)?;
+
+    if !access::is_allowed(&cfg.access_rules, req.method(), original_path) {
+        tracing::warn!("Denied {} {} by access rules", req.method(), original_path);
+        return Err(StatusCode::FORBIDDEN);
+    }
+
+    if cfg.answer_options && req.method() == http::Method::OPTIONS {
+        return Ok(allow_response(PROXY_METHODS));
+    }
+
+    let html_client = accepts_html(req.headers());
+    match handler_impl(client, cfg, pages.clone(), state, req).await {
+        Ok(response) => Ok(response),
         Err(e) => {
-            tracing::error!("{}", e);
-            Err(StatusCode::INTERNAL_SERVER_ERROR)
+            let (status, description) = classify_error(&e);
+            if status == StatusCode::GATEWAY_TIMEOUT {
+                // a slow upstream, not a proxy failure; the message names the URI
+                tracing::warn!("{}: {:#}", description, e);
+            } else {
+                tracing::error!("{}: {:#}", description, e);
+            }
+            match pages.error(status) {
+                Some(page) if html_client => Ok(page),
+                _ => Ok((status, description).into_response()),
+            }
         }
     }
 }
 
-async fn ws(Extension(cfg): Extension<Arc<Cfg>>, ws: WebSocketUpgrade, req: Request) -> impl IntoResponse {
-    ws.on_upgrade(|ws| handle_socket(ws, cfg, req))
+/// 431 for requests over `max_header_count` or `max_header_value_len`
+fn check_header_limits(req: &Request, cfg: &Cfg) -> Result<(), StatusCode> {
+    if req.headers().len() > cfg.max_header_count {
+        tracing::warn!(
+            "Rejecting {} {}: {} header fields exceed max_header_count of {}",
+            req.method(), req.uri(), req.headers().len(), cfg.max_header_count
+        );
+        return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+    if let Some((name, value)) = req.headers().iter().find(|(_, value)| value.len() > cfg.max_header_value_len) {
+        tracing::warn!(
+            "Rejecting {} {}: {} header of {} bytes exceeds max_header_value_len of {}",
+            req.method(), req.uri(), name, value.len(), cfg.max_header_value_len
+        );
+        return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+    Ok(())
 }
 
-async fn handle_socket(proxy_socket: WebSocket, cfg: Arc<Cfg>, req: Request) {
-    async fn handler_impl(proxy_socket: WebSocket, cfg: Arc<Cfg>, req: Request) -> anyhow::Result<()> {
-        let path = req.uri().path();
-        let path_query = req
-            .uri()
-            .path_and_query()
-            .map(|v| v.as_str())
-            .unwrap_or(path);
+/// Tells failures to reach the upstream (502) and upstream timeouts (504) apart from
+/// the proxy's own errors (500)
+fn classify_error(e: &anyhow::Error) -> (StatusCode, &'static str) {
+    let timed_out = e.chain().any(|cause| {
+        cause.is::<tokio::time::error::Elapsed>()
+            || cause.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut)
+    });
+    if timed_out {
+        return (StatusCode::GATEWAY_TIMEOUT, "Upstream timed out");
+    }
+    let connect_failed = e.chain()
+        .filter_map(|cause| cause.downcast_ref::<hyper_util::client::legacy::Error>())
+        .any(hyper_util::client::legacy::Error::is_connect);
+    if connect_failed {
+        return (StatusCode::BAD_GATEWAY, "Could not connect to upstream");
+    }
+    // hyper errors also cover an upstream response body failing while it's buffered
+    if e.chain().any(|cause| cause.is::<hyper_util::client::legacy::Error>() || cause.is::<hyper::Error>()) {
+        return (StatusCode::BAD_GATEWAY, "Upstream request failed");
+    }
+    (StatusCode::INTERNAL_SERVER_ERROR, "Proxy error")
+}
 
-            let uri = format!("wss://{}{}", cfg.host, path_query);
-            tracing::info!("WS {}", uri);
-            
-            let mut request = Request::builder()
-                .uri(uri);
-            
-            let headers = request.headers_mut().context("No headers in request")?;
-            for (key, value) in req.headers() {
-                if key == http::header::HOST {
-                    headers.insert(key, cfg.host.parse()?);
-                } else {
-                    headers.insert(key, value.to_owned());
-                }
-            }
+type UpstreamWebSocket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn ws(
+    Extension(cfg): Extension<Arc<Cfg>>,
+    Extension(tls_config): Extension<Arc<rustls::ClientConfig>>,
+    Extension(state): Extension<Arc<State>>,
+    shutdown: Option<Extension<Shutdown>>,
+    ws: Result<WebSocketUpgrade, WebSocketUpgradeRejection>,
+    req: Request
+) -> axum::response::Response {
+    if state.maintenance() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Service is under maintenance").into_response();
+    }
+    if state.is_route_disabled(req.uri().path()) {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Route is disabled").into_response();
+    }
+    if let Err(status) = check_header_limits(&req, &cfg) {
+        return status.into_response();
+    }
+    if !access::is_allowed(&cfg.access_rules, req.method(), req.uri().path()) {
+        tracing::warn!("Denied {} {} by access rules", req.method(), req.uri().path());
+        return StatusCode::FORBIDDEN.into_response();
+    }
 
-            request.headers_mut().unwrap().insert("Sec-WebSocket-Key", generate_key().parse()?);
-            
-            let request = request.body(()).unwrap();
+    let id = state.ws_upgrade_attempted();
+    let span = tracing::info_span!("ws", instance = %cfg.instance_name, id);
+    let ws = match ws {
+        Ok(ws) => ws,
+        Err(rejection) => {
+            span.in_scope(|| tracing::warn!("Rejected WebSocket upgrade: {}", rejection));
+            state.ws_upgrade_rejected(WsRejection::InvalidRequest);
+            return rejection.into_response();
+        }
+    };
+
+    // the upstream handshake goes first so the client's 101 can carry the upstream's subprotocol
+    let (parts, _) = req.into_parts();
+    let host = cfg.ws_host.as_deref().unwrap_or_else(|| state.next_upstream(&cfg.host));
+    let connected = connect_upstream_ws(&cfg, host, tls_config, &parts).instrument(span.clone()).await;
+    let (pa_ws_stream, protocol) = match connected {
+        Ok(connected) => connected,
+        Err(e) => {
+            span.in_scope(|| tracing::error!("WebSocket upstream connection failed: {}", e));
+            state.ws_upgrade_rejected(WsRejection::UpstreamFailed);
+            return StatusCode::BAD_GATEWAY.into_response();
+        }
+    };
+    state.ws_upgrade_succeeded();
+
+    let ws = match protocol {
+        Some(protocol) => ws.protocols([protocol]),
+        None => ws,
+    };
+    let route = parts.uri.path().to_owned();
+    let client = parts.extensions.get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| *addr);
+    // the upgraded socket is served from its own task, outside the request span
+    ws.on_upgrade(move |ws| {
+        let open = span.in_scope(|| state.websocket_opened(id, route, client));
+        let shutdown = shutdown.map(|Extension(shutdown)| shutdown);
+        handle_socket(ws, pa_ws_stream, cfg, state, open, shutdown).instrument(span)
+    })
+}
+
+/// Opens the upstream WebSocket, returning it with the subprotocol the upstream selected
+async fn connect_upstream_ws(
+    cfg: &Cfg,
+    host: &str,
+    tls_config: Arc<rustls::ClientConfig>,
+    req: &http::request::Parts
+) -> anyhow::Result<(UpstreamWebSocket, Option<String>)> {
+    let path = cfg.ws_path.apply(req.uri.path());
+    let path_query = match req.uri.query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path,
+    };
+    let uri = format!("wss://{}{}", host, path_query);
+    tracing::info!("WS {}", uri);
+
+    let mut request = Request::builder()
+        .uri(uri);
 
-        let config = Arc::new(build_client_config());
+    let headers = request.headers_mut().context("No headers in request")?;
+    for (key, value) in &req.headers {
+        if key == http::header::HOST {
+            headers.insert(key, host.parse()?);
+        } else if key == http::header::SEC_WEBSOCKET_EXTENSIONS || key == http::header::SEC_WEBSOCKET_PROTOCOL {
+            // tungstenite implements no extensions (permessage-deflate included),
+            // and the protocol offer is normalized below
+            continue;
+        } else {
+            headers.insert(key, value.to_owned());
+        }
+    }
+
+    // tungstenite checks the upstream's choice against the offer split on bare commas
+    let protocols = req.headers
+        .get_all(http::header::SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+        .collect::<Vec<_>>()
+        .join(",");
+    if !protocols.is_empty() {
+        headers.insert(http::header::SEC_WEBSOCKET_PROTOCOL, protocols.parse()?);
+    }
+
+    headers.insert(http::header::SEC_WEBSOCKET_KEY, generate_key().parse()?);
+    if let Some(user_agent) = &cfg.user_agent {
+        apply_user_agent(headers, user_agent);
+    }
 
-        let (pa_ws_stream, _) = connect_async_tls_with_config(
-            request,
-            None,
-            false,
-            Some(tokio_tungstenite::Connector::Rustls(config))
-        ).await?;
+    let request = request.body(())?;
 
+    let (pa_ws_stream, response) = connect_async_tls_with_config(
+        request,
+        None,
+        false,
+        Some(tokio_tungstenite::Connector::Rustls(tls_config))
+    ).await?;
+
+    let protocol = response.headers()
+        .get(http::header::SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
+
+    Ok((pa_ws_stream, protocol))
+}
+
+async fn handle_socket(
+    proxy_socket: WebSocket,
+    pa_ws_stream: UpstreamWebSocket,
+    cfg: Arc<Cfg>,
+    state: Arc<State>,
+    open: WebSocketGuard,
+    shutdown: Option<Shutdown>
+) {
+    async fn handler_impl(
+        proxy_socket: WebSocket,
+        pa_ws_stream: UpstreamWebSocket,
+        cfg: Arc<Cfg>,
+        state: Arc<State>,
+        entry: Arc<WebSocketEntry>,
+        mut shutdown: Option<Shutdown>
+    ) -> anyhow::Result<()> {
         let (mut pa_ws_writer, mut pa_ws_reader) =
             pa_ws_stream.split();
 
         let (mut proxy_ws_writer, mut proxy_ws_reader) =
             proxy_socket.split();
 
-        tokio::spawn( async move {
+        // reads keep going while the opposite writer catches up; a full buffer means
+        // that peer can't keep up and the whole session is dropped
+        let (to_client_tx, mut to_client_rx) = mpsc::channel(cfg.ws_buffer_capacity);
+        let (to_upstream_tx, mut to_upstream_rx) = mpsc::channel(cfg.ws_buffer_capacity);
+
+        let upstream_cfg = cfg.clone();
+        let upstream_entry = entry.clone();
+        let upstream_state = state.clone();
+        let upstream_reader = async move {
             while let Some(msg) = pa_ws_reader.next().await {
-                let msg = if let Ok(msg) = msg {
-                    msg
-                } else {
-                    return;
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(e) => {
+                        tracing::warn!("Upstream WebSocket closed abruptly: {}", e);
+                        let close = Message::Close(Some(CloseFrame {
+                            code: close_code::ERROR,
+                            reason: "Upstream connection lost".into(),
+                        }));
+                        let _ = to_client_tx.try_send(close);
+                        break;
+                    }
                 };
 
                 let ws_msg = if let Some(msg) = tungstein_to_axum(msg) {
@@ -118,69 +529,307 @@ async fn handle_socket(proxy_socket: WebSocket, cfg: Arc<Cfg>, req: Request) {
                 } else {
                     continue
                 };
+                let ws_msg = match ws_msg {
+                    Message::Text(text) => Message::Text(
+                        replace_text(text, &upstream_cfg.ws_text_replacements, Direction::ToClient)
+                    ),
+                    msg => msg,
+                };
+
+                let len = payload_len(&ws_msg);
+                upstream_entry.sent_to_client(len);
+                upstream_state.update_ws_traffic(len);
+                match to_client_tx.try_send(ws_msg) {
+                    Ok(()) => {},
+                    Err(TrySendError::Full(_)) => anyhow::bail!("WebSocket client is too slow, closing connection"),
+                    Err(TrySendError::Closed(_)) => break,
+                }
+            }
+            Ok(())
+        };
 
-                if let Err(e) = proxy_ws_writer.send(ws_msg).await {
+        // set once either side is sent its Close, the other one gets `close_timeout` to wind down
+        let closing = Arc::new(Notify::new());
+        let close_timeout = Duration::from_millis(cfg.ws_close_timeout_ms);
+
+        let send_timeout = cfg.ws_send_timeout_ms.map(Duration::from_millis);
+        let client_closing = closing.clone();
+        // the writers only borrow their sinks, which are still around to close an aborted session
+        let client_sink = &mut proxy_ws_writer;
+        let client_writer = async move {
+            while let Some(msg) = to_client_rx.recv().await {
+                // a send only stays pending when the client stopped reading and its TCP window is full
+                let send = client_sink.send(msg);
+                let sent = match send_timeout {
+                    Some(send_timeout) => tokio::time::timeout(send_timeout, send)
+                        .await
+                        .map_err(|_| anyhow::anyhow!(
+                            "WebSocket client stopped reading for {:?}, closing connection", send_timeout
+                        ))?,
+                    None => send.await,
+                };
+                if let Err(e) = sent {
                     tracing::info!("WebSocket error: {}", e);
+                    break;
                 }
             }
-        });
+            client_closing.notify_one();
+            let _ = client_sink.close().await;
+            Ok(())
+        };
 
-        while let Some(msg) = proxy_ws_reader.next().await {
-            let msg = if let Ok(msg) = msg {
-                msg
-            } else {
-                // client disconnected
-                return Ok(());
-            };
+        let client_cfg = cfg.clone();
+        let client_reader = async move {
+            // stops once the client disconnected
+            while let Some(Ok(msg)) = proxy_ws_reader.next().await {
+                let msg = match msg {
+                    Message::Text(text) => Message::Text(
+                        replace_text(text, &client_cfg.ws_text_replacements, Direction::ToUpstream)
+                    ),
+                    msg => msg,
+                };
+                let len = payload_len(&msg);
+                entry.sent_to_upstream(len);
+                state.update_ws_traffic(len);
+                match to_upstream_tx.try_send(axum_to_tungstein(msg)) {
+                    Ok(()) => {},
+                    Err(TrySendError::Full(_)) => anyhow::bail!("WebSocket upstream is too slow, closing connection"),
+                    Err(TrySendError::Closed(_)) => break,
+                }
+            }
+            Ok(())
+        };
+
+        let upstream_closing = closing.clone();
+        let upstream_sink = &mut pa_ws_writer;
+        let upstream_writer = async move {
+            while let Some(msg) = to_upstream_rx.recv().await {
+                upstream_sink.send(msg).await?;
+            }
+            upstream_closing.notify_one();
+            let _ = upstream_sink.close().await;
+            Ok(())
+        };
 
-            pa_ws_writer.send(axum_to_tungstein(msg)).await?;
+        let proxy = async {
+            tokio::try_join!(upstream_reader, client_writer, client_reader, upstream_writer)
+        };
+        let mut shutting_down = false;
+        let result = tokio::select! {
+            result = proxy => result.map(|_| ()),
+            _ = async {
+                match &mut shutdown {
+                    Some(shutdown) => shutdown.wait().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                tracing::info!("Closing WebSocket, the proxy is shutting down");
+                shutting_down = true;
+                Ok(())
+            },
+            _ = async {
+                closing.notified().await;
+                tokio::time::sleep(close_timeout).await;
+            } => {
+                // dropping both sockets closes the underlying connections
+                tracing::warn!("WebSocket close handshake timed out after {:?}, dropping connection", close_timeout);
+                Ok(())
+            },
+        };
+
+        // an error or a shutdown aborts the relay mid-session, neither peer has been sent a Close
+        // yet; one that stopped reading may not take it, hence the timeout
+        let close = match &result {
+            Err(_) => Some((close_code::ERROR, "Proxy error")),
+            Ok(()) if shutting_down => Some((close_code::AWAY, "Proxy shutting down")),
+            Ok(()) => None,
+        };
+        if let Some((code, reason)) = close {
+            let close = Message::Close(Some(CloseFrame { code, reason: reason.into() }));
+            let _ = tokio::time::timeout(close_timeout, async {
+                tokio::join!(
+                    proxy_ws_writer.send(close.clone()),
+                    pa_ws_writer.send(axum_to_tungstein(close)),
+                )
+            }).await;
         }
 
-        Ok(())
+        result
     }
 
 
-    if let Err(e) = handler_impl(proxy_socket, cfg, req).await {
+    // `shutdown` is held until the relay is done, `server::serve` waits for it
+    if let Err(e) = handler_impl(proxy_socket, pa_ws_stream, cfg, state, open.entry(), shutdown).await {
         tracing::error!("{}", e);
     };
 }
 
-fn get_static_serve_service(path: &String, sub_path: Option<&str>) -> ServeDir {
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    uptime_secs: u64,
+}
+
+/// Liveness only, unlike `/readyz` it never depends on the upstream
+async fn healthz(Extension(state): Extension<Arc<State>>) -> Json<Health> {
+    Json(Health { status: "ok", uptime_secs: state.uptime_secs() })
+}
+
+async fn readyz(Extension(state): Extension<Arc<State>>) -> impl IntoResponse {
+    if state.ready() {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "not ready")
+    }
+}
+
+/// Answers `/favicon.ico` locally unless it's configured to be proxied
+fn with_favicon(router: Router, cfg: &Cfg) -> Router {
+    match &cfg.favicon {
+        Favicon::Proxy => router,
+        Favicon::NoContent => router.route("/favicon.ico", get(|| async { StatusCode::NO_CONTENT })),
+        Favicon::File(path) => router.route_service("/favicon.ico", ServeFile::new(path)),
+    }
+}
+
+fn get_static_serve_service(path: &String, sub_path: Option<&str>, cfg: &Cfg) -> MethodRouter {
     let path = sub_path
         .map(|sub_path| [path, sub_path].iter().collect::<PathBuf>())
         .unwrap_or(path.into());
 
-    ServeDir::new(path)
+    let serve_dir = serve_dir(path, cfg);
+    let service = match &cfg.static_fallback {
+        StaticFallback::Default => any_service(serve_dir),
+        StaticFallback::Proxy => any_service(
+            serve_dir
+                .fallback(handler.into_service())
+                .call_fallback_on_method_not_allowed(true)
+        ),
+        StaticFallback::Page(page) => any_service(serve_dir.not_found_service(ServeFile::new(page))),
+    };
+    with_vary(with_local_options(service, cfg, "GET, HEAD, OPTIONS"), cfg)
+        .layer(middleware::from_fn(count_static_bytes))
+}
+
+/// Records static response sizes apart from proxied ones; misses forwarded by
+/// `static_fallback: proxy` end up in both
+async fn count_static_bytes(
+    Extension(state): Extension<Arc<State>>,
+    req: Request,
+    next: middleware::Next
+) -> axum::response::Response {
+    next.run(req)
+        .await
+        .map(|body| count_bytes(body, move |size| state.record_static_size(size)))
+}
+
+fn serve_dir(path: impl AsRef<Path>, cfg: &Cfg) -> ServeDir {
+    let serve_dir = ServeDir::new(path);
+    if cfg.precompressed_static {
+        serve_dir.precompressed_br().precompressed_gzip()
+    } else {
+        serve_dir
+    }
+}
+
+fn with_vary(service: MethodRouter, cfg: &Cfg) -> MethodRouter {
+    if cfg.precompressed_static {
+        // ServeDir picks the variant by Accept-Encoding but doesn't tell caches so
+        service.layer(SetResponseHeaderLayer::appending(
+            http::header::VARY,
+            http::HeaderValue::from_static("accept-encoding"),
+        ))
+    } else {
+        service
+    }
+}
+
+/// `TraceLayer` whose request spans carry the instance name, logging finished
+/// responses and failures at the levels from `trace_levels`
+pub fn trace_layer(cfg: &Cfg) -> TraceLayer<
+    SharedClassifier<ServerErrorsAsFailures>,
+    impl Fn(&Request) -> tracing::Span + Clone,
+    DefaultOnRequest,
+    DefaultOnResponse,
+    DefaultOnBodyChunk,
+    DefaultOnEos,
+    DefaultOnFailure,
+> {
+    let instance = cfg.instance_name.clone();
+    let levels = &cfg.trace_levels;
+    // at info whatever the response level, so every line logged for the request at the default
+    // level carries the instance
+    let make_span = move |req: &Request| tracing::info_span!(
+        "request",
+        instance = %instance,
+        method = %req.method(),
+        uri = %req.uri(),
+        version = ?req.version(),
+    );
+
+    TraceLayer::new_for_http()
+        .make_span_with(make_span)
+        .on_response(
+            DefaultOnResponse::new()
+                .level(levels.response)
+                .latency_unit(LatencyUnit::Millis)
+        )
+        .on_failure(
+            DefaultOnFailure::new()
+                .level(levels.failure)
+                .latency_unit(LatencyUnit::Millis)
+        )
 }
 
-pub fn get_router(cfg: Arc<Cfg>) -> anyhow::Result<Router> {
-    let client = build_https_client()?;
-    if cfg.pagrid {
-        Ok(get_pag_router(cfg, client))
+pub fn get_router(
+    cfg: Arc<Cfg>,
+    client: HTTPSClient,
+    tls_config: Arc<rustls::ClientConfig>,
+    state: Arc<State>
+) -> anyhow::Result<Router> {
+    let pages = Pages::load(&cfg)?;
+    let mut router = if cfg.pagrid {
+        get_pag_router(cfg.clone(), client, pages)
     } else {
-        Ok(get_pa6_router(cfg, client))
+        get_pa6_router(cfg.clone(), client, pages)
+    };
+    if let Some(timeout_ms) = cfg.request_timeout_ms {
+        let timeout = Duration::from_millis(timeout_ms);
+        router = router.layer(middleware::from_fn_with_state(timeout, request_timeout));
     }
+    // covers static mounts as well as proxied responses
+    for (name, value) in &cfg.response_headers {
+        router = router.layer(SetResponseHeaderLayer::overriding(name.clone(), value.clone()));
+    }
+    Ok(router
+        .layer(Extension(tls_config))
+        .layer(Extension(state)))
 
 }
 
-fn get_pa6_router(cfg: Arc<Cfg>, client: HTTPSClient) -> Router {
-    Router::new()
+fn get_pa6_router(cfg: Arc<Cfg>, client: HTTPSClient, pages: Pages) -> Router {
+    let router = Router::new()
         .nest_service(
             "/polyanalyst/static", 
-            get_static_serve_service(&cfg.sourcedata, None)
+            get_static_serve_service(&cfg.sourcedata, None, &cfg)
         )
         .nest_service(
             "/polyanalyst/help", 
-            get_static_serve_service(&cfg.help, None)
+            get_static_serve_service(&cfg.help, None, &cfg)
         )
-        .route("/polyanalyst/eventsSocket", get(ws))
+        .route("/polyanalyst/eventsSocket", with_local_options(get(ws), &cfg, "GET, OPTIONS"))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz));
+
+    with_favicon(router, &cfg)
         .fallback(handler)
         .layer(Extension(client))
         .layer(Extension(cfg.clone()))
-        .layer(TraceLayer::new_for_http())
+        .layer(Extension(pages))
+        .layer(trace_layer(&cfg))
 }
 
-fn get_pag_router(cfg: Arc<Cfg>, client: HTTPSClient) -> Router {
+fn get_pag_router(cfg: Arc<Cfg>, client: HTTPSClient, pages: Pages) -> Router {
     let static_paths: Vec<(&str, Option<&str>)> = vec![
         ("/fonts", Some("fonts")),
         ("/vendor", Some("vendor")),
@@ -191,20 +840,228 @@ fn get_pag_router(cfg: Arc<Cfg>, client: HTTPSClient) -> Router {
     ];
 
     let mut router = Router::new()
-        .route("/ws", get(ws))
-        .route("/api", get(handler).post(handler));
+        .route("/ws", with_local_options(get(ws), &cfg, "GET, OPTIONS"))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/api", with_local_options(get(handler).post(handler), &cfg, "GET, HEAD, POST, OPTIONS"));
 
     for (route, sub_path) in static_paths {
-        router = router.nest_service(route, get_static_serve_service(&cfg.sourcedata, sub_path));
+        router = router.nest_service(route, get_static_serve_service(&cfg.sourcedata, sub_path, &cfg));
+    }
+
+    router = router.nest_service(
+        "/help", 
+        get_static_serve_service(&cfg.help, None, &cfg)
+    );
+    router = with_favicon(router, &cfg);
+
+    if cfg.static_catch_all {
+        // any other file under sourcedata, GET/HEAD only; directories, missing files
+        // and other methods are proxied, and API calls never touch the disk
+        let catch_all = serve_dir(&cfg.sourcedata, &cfg)
+            .append_index_html_on_directories(false)
+            .fallback(handler.into_service())
+            .call_fallback_on_method_not_allowed(true);
+        router = router
+            .route("/api/*path", any(handler))
+            .fallback_service(with_vary(any_service(catch_all), &cfg));
+    } else {
+        router = router.fallback(handler);
     }
 
     router
-        .nest_service(
-            "/help", 
-            get_static_serve_service(&cfg.help, None)
-        )
-        .fallback(handler)
         .layer(Extension(client))
         .layer(Extension(cfg.clone()))
-        .layer(TraceLayer::new_for_http())
+        .layer(Extension(pages))
+        .layer(trace_layer(&cfg))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, routing::post};
+    use http::HeaderMap;
+    use tokio_tungstenite::tungstenite::{protocol::frame::coding::CloseCode, Message as TungsteniteWsMessage};
+
+    use super::*;
+    use crate::{
+        server::{self, ServeOptions},
+        testing::{body_text, capture_logs, mock_upstream, proxy_router, proxy_router_with_state, test_config, TestClient},
+    };
+
+    #[tokio::test]
+    async fn proxies_requests_to_the_upstream() {
+        let upstream = mock_upstream(Router::new().route("/api", get(|| async { "hello from upstream" }))).await;
+        let client = TestClient::new(proxy_router(&test_config(upstream, "")));
+
+        let response = client.get("/api").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_text(response).await, "hello from upstream");
+    }
+
+    #[tokio::test]
+    async fn proxied_requests_are_counted_in_the_shared_state() {
+        let upstream = mock_upstream(Router::new().route("/api", get(|| async { "hello from upstream" }))).await;
+        let state = Arc::new(State::default());
+        let client = TestClient::new(proxy_router_with_state(&test_config(upstream, ""), state.clone()));
+
+        let response = client.get("/api").await;
+        assert_eq!(body_text(response).await, "hello from upstream");
+
+        let stats = state.stats();
+        assert_eq!(stats.traffic["/api"].requests, 1);
+        assert!(stats.traffic["/api"].received > 0);
+        assert_eq!(stats.response_body_sizes.under_1kb, 1);
+    }
+
+    #[tokio::test]
+    async fn expect_continue_is_not_sent_upstream() {
+        let echo = |headers: HeaderMap, body: String| async move {
+            format!("expect={} body={}", headers.contains_key(http::header::EXPECT), body)
+        };
+        let upstream = mock_upstream(Router::new().route("/api", post(echo))).await;
+        let client = TestClient::new(proxy_router(&test_config(upstream, "")));
+
+        let request = Request::post("/api")
+            .header(http::header::EXPECT, "100-continue")
+            .body(Body::from("upload"))
+            .unwrap();
+        let response = client.request(request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(body_text(response).await, "expect=false body=upload");
+    }
+
+    #[tokio::test]
+    async fn slow_upstream_is_answered_with_gateway_timeout() {
+        let slow = || async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            "too late"
+        };
+        let upstream = mock_upstream(Router::new().route("/api", get(slow))).await;
+        let client = TestClient::new(proxy_router(&test_config(upstream, "timeout_secs: 1")));
+
+        let (logs, _subscriber) = capture_logs();
+        let response = client.get("/api").await;
+        assert_eq!(response.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(body_text(response).await, "Upstream timed out");
+        let logs = logs.text();
+        let line = logs.lines().find(|line| line.contains("Upstream timed out")).unwrap();
+        assert!(line.contains("WARN") && line.contains("/api within 1s"), "{}", logs);
+    }
+
+    #[tokio::test]
+    async fn request_timeout_keeps_the_body_length() {
+        let upstream = mock_upstream(Router::new().route("/api", get(|| async { "hello" }))).await;
+        let client = TestClient::new(proxy_router(&test_config(upstream, "request_timeout_ms: 1000")));
+
+        // hyper sends a Content-Length for bodies with an exact size hint
+        for path in ["/api", "/healthz"] {
+            let response = client.get(path).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let len = response.body().size_hint().exact();
+            assert_eq!(len, Some(body_text(response).await.len() as u64), "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn request_timeout_aborts_a_late_body() {
+        let late = || async {
+            let rest = futures_util::stream::once(async {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                "too late"
+            });
+            let chunks = futures_util::stream::iter(["on time"]).chain(rest);
+            Body::from_stream(chunks.map(Ok::<_, std::convert::Infallible>))
+        };
+        let upstream = mock_upstream(Router::new().route("/api", get(late))).await;
+        let client = TestClient::new(proxy_router(&test_config(upstream, "request_timeout_ms: 500")));
+
+        let response = client.get("/api").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(axum::body::to_bytes(response.into_body(), usize::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstream_is_answered_with_bad_gateway() {
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let client = TestClient::new(proxy_router(&test_config(closed, "")));
+
+        let response = client.get("/api").await;
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(body_text(response).await, "Could not connect to upstream");
+    }
+
+    #[tokio::test]
+    async fn websocket_route_applies_access_rules_and_header_limits() {
+        let closed = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        let rules = "access_rules:\n  - path: /ws/**\n    action: deny\nmax_header_count: 5";
+        let client = TestClient::new(proxy_router(&test_config(closed, rules)));
+
+        assert_eq!(client.get("/ws").await.status(), StatusCode::FORBIDDEN);
+        assert_eq!(client.get("/WS/").await.status(), StatusCode::FORBIDDEN);
+
+        let mut request = Request::get("/ws");
+        for i in 0..6 {
+            request = request.header(format!("x-extra-{}", i), "1");
+        }
+        let response = client.request(request.body(Body::empty()).unwrap()).await;
+        assert_eq!(response.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn upstream_close_reaches_the_client() {
+        let close = |ws: WebSocketUpgrade| async {
+            ws.on_upgrade(|mut socket| async move {
+                let frame = CloseFrame { code: close_code::ERROR, reason: "backend restarting".into() };
+                let _ = socket.send(Message::Close(Some(frame))).await;
+            })
+        };
+        let upstream = mock_upstream(Router::new().route("/ws", get(close))).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = proxy_router(&test_config(upstream, ""));
+        tokio::spawn(server::serve(listener, router, ServeOptions::default(), std::future::pending()));
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.unwrap();
+        match message {
+            Some(Ok(TungsteniteWsMessage::Close(Some(frame)))) => {
+                assert_eq!(frame.code, CloseCode::Error);
+                assert_eq!(frame.reason, "backend restarting");
+            }
+            other => panic!("expected the upstream's close frame, got {:?}", other),
+        }
+    }
+    #[tokio::test]
+    async fn shutdown_closes_websockets_as_going_away() {
+        let (upstream_close_tx, mut upstream_close_rx) = mpsc::unbounded_channel();
+        let record_close = move |ws: WebSocketUpgrade| async move {
+            ws.on_upgrade(move |mut socket| async move {
+                while let Some(Ok(msg)) = socket.recv().await {
+                    if let Message::Close(frame) = msg {
+                        let _ = upstream_close_tx.send(frame.map(|frame| frame.code));
+                    }
+                }
+            })
+        };
+        let upstream = mock_upstream(Router::new().route("/ws", get(record_close))).await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let router = proxy_router(&test_config(upstream, ""));
+        let (signal_tx, signal_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(server::serve(listener, router, ServeOptions::default(), async {
+            let _ = signal_rx.await;
+        }));
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/ws", addr)).await.unwrap();
+        signal_tx.send(()).unwrap();
+
+        let message = tokio::time::timeout(Duration::from_secs(5), socket.next()).await.unwrap();
+        match message {
+            Some(Ok(TungsteniteWsMessage::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Away),
+            other => panic!("expected a going away close frame, got {:?}", other),
+        }
+        let upstream_close = tokio::time::timeout(Duration::from_secs(5), upstream_close_rx.recv()).await.unwrap();
+        assert_eq!(upstream_close, Some(Some(close_code::AWAY)));
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap().unwrap();
+    }
 }
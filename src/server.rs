@@ -0,0 +1,429 @@
+use std::{
+    fmt::Display,
+    future::Future,
+    io::{self, IoSlice},
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, Router};
+use hyper::{body::Incoming, server::conn::http1, Request};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, BufReader, ReadBuf},
+    net::{TcpListener, TcpSocket, TcpStream},
+    sync::{watch, Notify, OwnedSemaphorePermit, Semaphore},
+};
+use tower::ServiceExt;
+
+use crate::{cfg::Cfg, proxy_protocol, state::{ConnectionGuard, State}};
+
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+/// Pause after running out of file descriptors, open connections get a chance to close
+const FD_EXHAUSTED_BACKOFF: Duration = Duration::from_secs(1);
+/// Pause after any other accept error, so a persistent one doesn't spin the loop
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Fires once `serve` begins shutting down. Requests carry one as an extension; `serve` doesn't
+/// return while any is held, so upgraded connections can keep it to finish first
+#[derive(Clone)]
+pub struct Shutdown(watch::Receiver<()>);
+
+impl Shutdown {
+    pub async fn wait(&mut self) {
+        let _ = self.0.changed().await;
+    }
+}
+
+/// Per-listener settings; the admin listener runs with the defaults
+#[derive(Clone, Default)]
+pub struct ServeOptions {
+    pub h2c: bool,
+    pub proxy_protocol: bool,
+    /// New connections wait in the listen backlog while this many are open
+    pub max_connections: Option<usize>,
+    /// Connections without a request or response for this long are closed
+    pub idle_timeout: Option<Duration>,
+    /// HTTP/1 requests with more header fields are answered 431 by hyper (its default: 100)
+    pub max_headers: Option<usize>,
+    /// Where open connections are counted, if anywhere
+    pub state: Option<Arc<State>>,
+}
+
+impl From<&Cfg> for ServeOptions {
+    fn from(cfg: &Cfg) -> Self {
+        Self {
+            h2c: cfg.h2c,
+            proxy_protocol: cfg.proxy_protocol,
+            max_connections: cfg.max_connections,
+            idle_timeout: cfg.client_idle_timeout_secs.map(Duration::from_secs),
+            max_headers: Some(cfg.max_header_count),
+            state: None,
+        }
+    }
+}
+
+/// Binds with an explicit listen backlog, which the OS silently caps
+/// (`net.core.somaxconn` on Linux, `kern.ipc.somaxconn` on macOS)
+pub fn bind(addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    // matches what TcpListener::bind does, so restarts don't trip over TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// Like `bind`, retrying `AddrInUse` while a previous instance releases the port
+pub async fn bind_with_retries(
+    addr: SocketAddr,
+    backlog: u32,
+    retries: u32,
+    delay: Duration
+) -> io::Result<TcpListener> {
+    let mut attempt = 0;
+    loop {
+        match bind(addr, backlog) {
+            Err(e) if e.kind() == io::ErrorKind::AddrInUse && attempt < retries => {
+                attempt += 1;
+                tracing::warn!("{} is in use, retrying bind in {:?} ({}/{})", addr, delay, attempt, retries);
+                tokio::time::sleep(delay).await;
+            },
+            result => return result,
+        }
+    }
+}
+
+/// Holds the connection's `max_connections` permit and open-connection count for as long
+/// as the socket lives, which is past the HTTP connection when it's upgraded to a WebSocket
+struct TrackedIo<I> {
+    io: I,
+    _permit: Option<OwnedSemaphorePermit>,
+    _connection: Option<ConnectionGuard>,
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for TrackedIo<I> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for TrackedIo<I> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>]
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.io.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+    )
+}
+
+/// EMFILE (process limit) or ENFILE (system limit)
+fn is_fd_exhaustion(e: &io::Error) -> bool {
+    cfg!(unix) && matches!(e.raw_os_error(), Some(23 | 24))
+}
+
+/// Where `serve` takes connections from, tests substitute a listener that fails
+pub trait Listener {
+    fn accept(&self) -> impl Future<Output = io::Result<(TcpStream, SocketAddr)>> + Send;
+}
+
+impl Listener for TcpListener {
+    fn accept(&self) -> impl Future<Output = io::Result<(TcpStream, SocketAddr)>> + Send {
+        TcpListener::accept(self)
+    }
+}
+
+/// Accept errors never end the serve loop, they're logged and retried after a pause
+async fn accept(listener: &impl Listener) -> Option<(TcpStream, SocketAddr)> {
+    match listener.accept().await {
+        Ok(conn) => Some(conn),
+        Err(e) if is_connection_error(&e) => None,
+        Err(e) if is_fd_exhaustion(&e) => {
+            tracing::warn!("Accept error: {}, retrying in {:?}", e, FD_EXHAUSTED_BACKOFF);
+            tokio::time::sleep(FD_EXHAUSTED_BACKOFF).await;
+            None
+        }
+        Err(e) => {
+            tracing::error!("Accept error: {}", e);
+            tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+            None
+        }
+    }
+}
+
+async fn drive_connection<C, E>(
+    conn: C,
+    graceful_shutdown: impl FnOnce(Pin<&mut C>),
+    mut close_rx: watch::Receiver<()>,
+    idle: Option<(Duration, Arc<Notify>)>
+)
+where
+    C: Future<Output = Result<(), E>>,
+    E: Display,
+{
+    let mut conn = std::pin::pin!(conn);
+    let idle_timeout = async {
+        let Some((timeout, activity)) = idle else {
+            return std::future::pending().await;
+        };
+        // restarts whenever a request comes in or a response goes out
+        while tokio::time::timeout(timeout, activity.notified()).await.is_ok() {}
+        tracing::debug!("Closing connection idle for {:?}", timeout);
+    };
+
+    let finished = tokio::select! {
+        result = conn.as_mut() => Some(result),
+        _ = close_rx.changed() => None,
+        _ = idle_timeout => None,
+    };
+    let result = match finished {
+        Some(result) => result,
+        None => {
+            // lets a response still in flight finish first
+            graceful_shutdown(conn.as_mut());
+            conn.await
+        }
+    };
+    if let Err(e) = result {
+        tracing::debug!("Connection error: {}", e);
+    }
+}
+
+async fn serve_connection<I>(
+    io: I,
+    remote_addr: SocketAddr,
+    router: Router,
+    options: &ServeOptions,
+    close_rx: watch::Receiver<()>
+)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tracing::debug!("Serving connection from {}", remote_addr);
+    let activity = Arc::new(Notify::new());
+    let request_activity = activity.clone();
+    let response_activity = activity.clone();
+    let shutdown = Shutdown(close_rx.clone());
+    let service = router
+        .map_request(move |mut req: Request<Incoming>| {
+            req.extensions_mut().insert(ConnectInfo(remote_addr));
+            req.extensions_mut().insert(shutdown.clone());
+            request_activity.notify_one();
+            req
+        })
+        .map_response(move |response| {
+            response_activity.notify_one();
+            response
+        });
+    let service = TowerToHyperService::new(service);
+    let io = TokioIo::new(io);
+    // an upgraded connection leaves hyper, so open WebSockets aren't subject to it
+    let idle = options.idle_timeout.map(|timeout| (timeout, activity));
+
+    // upgrades needed for websockets; the auto builder can't be restricted
+    // to HTTP/1 while allowing them, hence the plain http1 builder
+    // graceful shutdown waits out a half-received request head, hyper's header timeout doesn't
+    if options.h2c {
+        let mut builder = auto::Builder::new(TokioExecutor::new());
+        if let Some(idle_timeout) = options.idle_timeout {
+            builder.http1().timer(TokioTimer::new()).header_read_timeout(idle_timeout);
+        }
+        if let Some(max_headers) = options.max_headers {
+            builder.http1().max_headers(max_headers);
+        }
+        let conn = builder.serve_connection_with_upgrades(io, service);
+        drive_connection(conn, |conn| conn.graceful_shutdown(), close_rx, idle).await;
+    } else {
+        let mut builder = http1::Builder::new();
+        if let Some(idle_timeout) = options.idle_timeout {
+            builder.timer(TokioTimer::new()).header_read_timeout(idle_timeout);
+        }
+        if let Some(max_headers) = options.max_headers {
+            builder.max_headers(max_headers);
+        }
+        let conn = builder.serve_connection(io, service).with_upgrades();
+        drive_connection(conn, |conn| conn.graceful_shutdown(), close_rx, idle).await;
+    }
+}
+
+pub async fn serve(
+    listener: impl Listener,
+    router: Router,
+    options: ServeOptions,
+    signal: impl Future<Output = ()>
+) -> anyhow::Result<()> {
+    let (close_tx, close_rx) = watch::channel(());
+    let mut signal = std::pin::pin!(signal);
+    let limit = options.max_connections.map(|max| (max, Arc::new(Semaphore::new(max))));
+    let mut saturated = false;
+
+    loop {
+        let permit = match &limit {
+            Some((max, limit)) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => {
+                    saturated = false;
+                    Some(permit)
+                },
+                Err(_) => {
+                    if !saturated {
+                        tracing::warn!("{} connections open, holding new ones until some close", max);
+                        saturated = true;
+                    }
+                    tokio::select! {
+                        permit = limit.clone().acquire_owned() => Some(permit?),
+                        _ = &mut signal => break,
+                    }
+                },
+            },
+            None => None,
+        };
+
+        let (stream, remote_addr) = tokio::select! {
+            conn = accept(&listener) => match conn {
+                Some(conn) => conn,
+                None => continue,
+            },
+            _ = &mut signal => break,
+        };
+
+        let router = router.clone();
+        let close_rx = close_rx.clone();
+        let options = options.clone();
+        let stream = TrackedIo {
+            io: stream,
+            _permit: permit,
+            _connection: options.state.as_ref().map(State::connection_opened),
+        };
+
+        tokio::spawn(async move {
+            if !options.proxy_protocol {
+                serve_connection(stream, remote_addr, router, &options, close_rx).await;
+                return;
+            }
+
+            let mut stream = BufReader::new(stream);
+            let header = tokio::time::timeout(
+                PROXY_HEADER_TIMEOUT,
+                proxy_protocol::read_header(&mut stream)
+            ).await;
+            let client_addr = match header {
+                Ok(Ok(client_addr)) => client_addr.unwrap_or(remote_addr),
+                Ok(Err(e)) => {
+                    tracing::warn!("Dropping connection from {}: {}", remote_addr, e);
+                    return;
+                },
+                Err(_) => {
+                    tracing::warn!("Dropping connection from {}: no PROXY protocol header", remote_addr);
+                    return;
+                },
+            };
+            serve_connection(stream, client_addr, router, &options, close_rx).await;
+        });
+    }
+
+    drop(listener);
+    drop(close_rx);
+    let _ = close_tx.send(());
+    close_tx.closed().await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{atomic::{AtomicUsize, Ordering}, Mutex},
+        time::Instant,
+    };
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::testing::capture_logs;
+
+    /// Fails its first `failures` accepts with EMFILE, remembering when each accept was attempted
+    struct ExhaustedListener {
+        inner: TcpListener,
+        failures: AtomicUsize,
+        attempts: Arc<Mutex<Vec<Instant>>>,
+    }
+
+    impl Listener for ExhaustedListener {
+        async fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+            self.attempts.lock().unwrap().push(Instant::now());
+            if self.failures.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1)).is_ok() {
+                return Err(io::Error::from_raw_os_error(24));
+            }
+            TcpListener::accept(&self.inner).await
+        }
+    }
+
+    #[test]
+    fn only_descriptor_exhaustion_backs_off_longer() {
+        assert!(is_fd_exhaustion(&io::Error::from_raw_os_error(24)));
+        assert!(is_fd_exhaustion(&io::Error::from_raw_os_error(23)));
+        assert!(!is_fd_exhaustion(&io::Error::from(io::ErrorKind::ConnectionReset)));
+        assert!(is_connection_error(&io::Error::from(io::ErrorKind::ConnectionReset)));
+        assert!(!is_connection_error(&io::Error::from_raw_os_error(24)));
+    }
+
+    // single-threaded, so the spawned serve loop logs to this test's subscriber
+    #[tokio::test(flavor = "current_thread")]
+    async fn serving_resumes_after_running_out_of_descriptors() {
+        let (logs, _subscriber) = capture_logs();
+
+        let inner = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = inner.local_addr().unwrap();
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let listener = ExhaustedListener { inner, failures: AtomicUsize::new(2), attempts: attempts.clone() };
+        let router = Router::new().route("/", axum::routing::get(|| async { "up" }));
+        tokio::spawn(serve(listener, router, ServeOptions::default(), std::future::pending()));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: x\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(10), stream.read_to_string(&mut response)).await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+        let attempts = attempts.lock().unwrap();
+        assert!(attempts.len() >= 3);
+        for pause in attempts.windows(2).take(2) {
+            assert!(pause[1] - pause[0] >= FD_EXHAUSTED_BACKOFF);
+        }
+        let logs = logs.text();
+        assert_eq!(logs.matches("Accept error").count(), 2, "{}", logs);
+    }
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use axum::{body::Body, extract::Request};
+use http::{header, StatusCode, Uri};
+use tokio::sync::OwnedSemaphorePermit;
+
+use crate::tls::HTTPSClient;
+
+/// Bodiless copy of a GET request as it's sent upstream, aimed at `shadow_host`
+pub fn shadow_request(req: &Request, shadow_host: &str) -> anyhow::Result<Request> {
+    let path_query = req.uri().path_and_query().map(|v| v.as_str()).unwrap_or("/");
+    let uri = Uri::try_from(format!("https://{}{}", shadow_host, path_query))?;
+
+    let mut shadow = Request::new(Body::empty());
+    *shadow.method_mut() = req.method().clone();
+    *shadow.uri_mut() = uri;
+    *shadow.version_mut() = req.version();
+    *shadow.headers_mut() = req.headers().clone();
+    if shadow.headers().contains_key(header::HOST) {
+        shadow.headers_mut().insert(header::HOST, shadow_host.parse()?);
+    }
+    Ok(shadow)
+}
+
+/// Sends the shadow copy and discards its response, warning when its status
+/// differs from what the primary upstream answered; `timeout_secs` 0 waits indefinitely
+pub async fn send_shadow(
+    client: HTTPSClient,
+    request: Request,
+    upstream_status: StatusCode,
+    timeout_secs: u64,
+    _slot: OwnedSemaphorePermit,
+) {
+    let uri = request.uri().clone();
+    let send = client.request(request);
+    let result = match timeout_secs {
+        0 => send.await,
+        secs => match tokio::time::timeout(Duration::from_secs(secs), send).await {
+            Ok(result) => result,
+            Err(_) => return tracing::warn!("Shadow GET {} did not answer within {}s", uri, secs),
+        },
+    };
+    match result {
+        Ok(response) if response.status() != upstream_status => tracing::warn!(
+            "Shadow GET {} answered {}, upstream answered {}", uri, response.status(), upstream_status
+        ),
+        Ok(_) => tracing::debug!("Shadow GET {} answered {} like upstream", uri, upstream_status),
+        Err(e) => tracing::warn!("Shadow GET {} failed: {}", uri, e),
+    }
+}
@@ -1,5 +1,35 @@
+use std::{fmt, sync::Arc};
+
+use crate::state::State;
+
+#[derive(Debug, Clone, Copy)]
+pub enum ShutdownSignal {
+    Interrupt,
+    #[cfg_attr(windows, allow(dead_code))]
+    Terminate,
+}
+
+impl ShutdownSignal {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            // 128 + SIGINT, as shells report it
+            ShutdownSignal::Interrupt => 130,
+            ShutdownSignal::Terminate => 0,
+        }
+    }
+}
+
+impl fmt::Display for ShutdownSignal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShutdownSignal::Interrupt => write!(f, "SIGINT"),
+            ShutdownSignal::Terminate => write!(f, "SIGTERM"),
+        }
+    }
+}
+
 #[cfg(unix)]
-pub async fn shutdown_signal() {
+pub async fn shutdown_signal() -> ShutdownSignal {
     use std::io;
     use tokio::signal::unix::SignalKind;
 
@@ -10,17 +40,66 @@ pub async fn shutdown_signal() {
         Ok(())
     }
 
-    tokio::select! {
-        _ = terminate() => {},
-        _ = tokio::signal::ctrl_c() => {},
-    }
-    tracing::info!("Signal received, starting graceful shutdown")
+    let signal = tokio::select! {
+        _ = terminate() => ShutdownSignal::Terminate,
+        _ = tokio::signal::ctrl_c() => ShutdownSignal::Interrupt,
+    };
+    tracing::info!("{} received, starting graceful shutdown", signal);
+    signal
 }
 
 #[cfg(windows)]
-pub async fn shutdown_signal() {
+pub async fn shutdown_signal() -> ShutdownSignal {
     tokio::signal::ctrl_c()
         .await
         .expect("failed to install CTRL+C handler");
-        tracing::info!("Signal received, starting graceful shutdown");
+    let signal = ShutdownSignal::Interrupt;
+    tracing::info!("{} received, starting graceful shutdown", signal);
+    signal
 }
+
+/// Paths with the most traffic listed in the SIGUSR1 dump
+#[cfg(unix)]
+const TOP_PATHS: usize = 5;
+
+/// Logs a stats snapshot on every SIGUSR1, for deployments without an admin listener
+#[cfg(unix)]
+pub async fn dump_stats_on_signal(instance: String, state: Arc<State>) {
+    use tokio::signal::unix::SignalKind;
+
+    let mut signal = match tokio::signal::unix::signal(SignalKind::user_defined1()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            tracing::warn!("Can't install SIGUSR1 handler, stats dump disabled: {}", e);
+            return;
+        }
+    };
+    while signal.recv().await.is_some() {
+        let stats = state.stats();
+        tracing::info!(
+            "Stats for {}, up {}: {} open connections, {} upstream connections opened, ready {}, maintenance {}",
+            instance, stats.uptime, stats.open_connections, stats.upstream_connections_opened, stats.ready, stats.maintenance
+        );
+        let ws = &stats.ws_upgrades;
+        tracing::info!(
+            "WebSockets: {} open, {} bytes relayed, upgrades {} attempted, {} succeeded, {} invalid, {} upstream failed",
+            stats.open_websockets, stats.websocket_traffic, ws.attempted, ws.succeeded, ws.rejected_invalid_request, ws.rejected_upstream_failed
+        );
+        tracing::info!("Request bodies: {}", stats.request_body_sizes);
+        tracing::info!("Response bodies: {}", stats.response_body_sizes);
+        tracing::info!("Static bodies: {}", stats.static_body_sizes);
+
+        let mut paths: Vec<_> = stats.traffic.iter().collect();
+        paths.sort_by_key(|(_, traffic)| std::cmp::Reverse(traffic.sent + traffic.received));
+        for (path, traffic) in paths.into_iter().take(TOP_PATHS) {
+            tracing::info!(
+                "{}: {} requests, {} bytes sent, {} bytes received",
+                path, traffic.requests, traffic.sent, traffic.received
+            );
+        }
+    }
+}
+
+/// There is no SIGUSR1 on Windows
+#[cfg(windows)]
+pub async fn dump_stats_on_signal(_instance: String, _state: Arc<State>) {}
@@ -0,0 +1,531 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    net::SocketAddr,
+    sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Mutex, PoisonError, RwLock},
+    time::Instant,
+};
+
+use serde_derive::Serialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::access::normalize_path;
+
+/// Runtime counters shared between the proxy handlers
+#[derive(Default)]
+pub struct State {
+    upstream_connections: AtomicU64,
+    open_connections: AtomicU64,
+    maintenance: AtomicBool,
+    ready: AtomicBool,
+    disabled_routes: RwLock<BTreeSet<String>>,
+    ws_upgrades_attempted: AtomicU64,
+    ws_upgrades_succeeded: AtomicU64,
+    ws_upgrades_invalid: AtomicU64,
+    ws_upgrades_upstream_failed: AtomicU64,
+    websockets: RwLock<BTreeMap<u64, Arc<WebSocketEntry>>>,
+    ws_traffic: AtomicU64,
+    request_sizes: SizeBuckets,
+    response_sizes: SizeBuckets,
+    static_sizes: SizeBuckets,
+    traffic: RwLock<BTreeMap<String, PathCounters>>,
+    started: StartTime,
+    next_upstream: AtomicUsize,
+    shadow_slots: ShadowSlots,
+}
+
+/// When the proxy came up, the state is created once at startup
+struct StartTime(Instant);
+
+impl Default for StartTime {
+    fn default() -> Self {
+        Self(Instant::now())
+    }
+}
+
+/// Shadow requests in flight at once, further copies are dropped until one finishes
+const MAX_SHADOW_REQUESTS: usize = 64;
+
+struct ShadowSlots(Arc<Semaphore>);
+
+impl Default for ShadowSlots {
+    fn default() -> Self {
+        Self(Arc::new(Semaphore::new(MAX_SHADOW_REQUESTS)))
+    }
+}
+
+/// Distinct request paths tracked in `traffic`, requests for further paths are counted under `OTHER_PATHS`
+const MAX_TRAFFIC_PATHS: usize = 1000;
+
+/// The `traffic` entry for paths past `MAX_TRAFFIC_PATHS`
+const OTHER_PATHS: &str = "(other)";
+
+/// Upper bounds of the body size buckets, the last bucket takes everything from 1MB up
+const SIZE_BUCKET_BOUNDS: [u64; 4] = [1024, 10 * 1024, 100 * 1024, 1024 * 1024];
+
+#[derive(Default)]
+struct SizeBuckets([AtomicU64; SIZE_BUCKET_BOUNDS.len() + 1]);
+
+impl SizeBuckets {
+    fn record(&self, size: u64) {
+        let bucket = SIZE_BUCKET_BOUNDS.iter()
+            .position(|&bound| size < bound)
+            .unwrap_or(SIZE_BUCKET_BOUNDS.len());
+        self.0[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> SizeHistogram {
+        let [under_1kb, under_10kb, under_100kb, under_1mb, from_1mb] =
+            self.0.each_ref().map(|count| count.load(Ordering::Relaxed));
+        SizeHistogram { under_1kb, under_10kb, under_100kb, under_1mb, from_1mb }
+    }
+}
+
+/// Why a WebSocket upgrade didn't result in a proxied session
+#[derive(Debug, Clone, Copy)]
+pub enum WsRejection {
+    /// Not a valid upgrade request, answered by axum's rejection
+    InvalidRequest,
+    /// Client upgraded but the upstream connection couldn't be established
+    UpstreamFailed,
+}
+
+/// Counts a client connection as open until dropped
+pub struct ConnectionGuard(Arc<State>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.open_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A proxied WebSocket, registered from upgrade to close
+pub struct WebSocketEntry {
+    route: String,
+    client: Option<SocketAddr>,
+    opened: Instant,
+    bytes_to_client: AtomicU64,
+    bytes_to_upstream: AtomicU64,
+}
+
+impl WebSocketEntry {
+    pub fn sent_to_client(&self, bytes: usize) {
+        self.bytes_to_client.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn sent_to_upstream(&self, bytes: usize) {
+        self.bytes_to_upstream.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Keeps a WebSocket registered as open until dropped
+pub struct WebSocketGuard {
+    state: Arc<State>,
+    id: u64,
+    entry: Arc<WebSocketEntry>,
+}
+
+impl WebSocketGuard {
+    pub fn entry(&self) -> Arc<WebSocketEntry> {
+        self.entry.clone()
+    }
+}
+
+impl Drop for WebSocketGuard {
+    fn drop(&mut self) {
+        let mut websockets = self.state.websockets.write().unwrap_or_else(PoisonError::into_inner);
+        websockets.remove(&self.id);
+        tracing::info!("WebSocket closed, {} open", websockets.len());
+    }
+}
+
+/// An open WebSocket as listed by `/__ws`, message payload bytes only
+#[derive(Serialize)]
+pub struct WebSocketInfo {
+    pub id: u64,
+    pub route: String,
+    pub client: Option<SocketAddr>,
+    pub open_secs: u64,
+    pub bytes_to_client: u64,
+    pub bytes_to_upstream: u64,
+}
+
+/// Live counters behind `PathTraffic`, updated under the map's read lock
+#[derive(Default)]
+struct PathCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    requests: AtomicU64,
+    statuses: Mutex<BTreeMap<u16, u64>>,
+}
+
+impl PathCounters {
+    fn snapshot(&self) -> PathTraffic {
+        PathTraffic {
+            sent: self.sent.load(Ordering::Relaxed),
+            received: self.received.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            statuses: self.statuses.lock().unwrap_or_else(PoisonError::into_inner).clone(),
+        }
+    }
+}
+
+/// Proxied requests and body bytes for one request path, in both directions
+#[derive(Serialize)]
+pub struct PathTraffic {
+    pub sent: u64,
+    pub received: u64,
+    /// Requests the upstream answered
+    pub requests: u64,
+    /// Upstream response status codes, counted
+    pub statuses: BTreeMap<u16, u64>,
+}
+
+#[derive(Serialize)]
+pub struct Stats {
+    pub upstream_connections_opened: u64,
+    pub open_connections: u64,
+    pub maintenance: bool,
+    pub ready: bool,
+    pub uptime_secs: u64,
+    /// `uptime_secs` for people, e.g. "3d 4h 12m"
+    pub uptime: String,
+    pub ws_upgrades: WsUpgradeStats,
+    pub open_websockets: u64,
+    /// Message payload bytes relayed over all WebSockets, both directions
+    pub websocket_traffic: u64,
+    pub request_body_sizes: SizeHistogram,
+    pub response_body_sizes: SizeHistogram,
+    pub static_body_sizes: SizeHistogram,
+    /// Keyed by request path without the query, paths past the first 1000 are summed under `(other)`
+    pub traffic: BTreeMap<String, PathTraffic>,
+}
+
+#[derive(Serialize)]
+pub struct SizeHistogram {
+    pub under_1kb: u64,
+    pub under_10kb: u64,
+    pub under_100kb: u64,
+    pub under_1mb: u64,
+    pub from_1mb: u64,
+}
+
+impl fmt::Display for SizeHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "<1KB {}, <10KB {}, <100KB {}, <1MB {}, >=1MB {}",
+            self.under_1kb, self.under_10kb, self.under_100kb, self.under_1mb, self.from_1mb
+        )
+    }
+}
+
+#[derive(Serialize)]
+pub struct WsUpgradeStats {
+    pub attempted: u64,
+    pub succeeded: u64,
+    pub rejected_invalid_request: u64,
+    pub rejected_upstream_failed: u64,
+}
+
+impl State {
+    /// Returns the total number of connections opened so far
+    pub fn upstream_connection_opened(&self) -> u64 {
+        self.upstream_connections.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+
+    /// Client connections to the proxy listener, WebSockets included
+    pub fn open_connections(&self) -> u64 {
+        self.open_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn open_websockets(&self) -> u64 {
+        self.websockets.read().unwrap_or_else(PoisonError::into_inner).len() as u64
+    }
+
+    pub fn maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    pub fn set_maintenance(&self, enabled: bool) {
+        self.maintenance.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Round-robin pick among the configured upstream hosts
+    pub fn next_upstream<'a>(&self, hosts: &'a [String]) -> &'a str {
+        let index = self.next_upstream.fetch_add(1, Ordering::Relaxed) % hosts.len();
+        &hosts[index]
+    }
+
+    pub fn uptime_secs(&self) -> u64 {
+        self.started.0.elapsed().as_secs()
+    }
+
+    /// Whether `/readyz` reports ready, see `probe_upstream`
+    pub fn ready(&self) -> bool {
+        self.ready.load(Ordering::Relaxed)
+    }
+
+    pub fn set_ready(&self, ready: bool) {
+        self.ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Path prefixes currently answered with 503
+    pub fn disabled_routes(&self) -> Vec<String> {
+        self.disabled_routes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Whether `path` is a disabled prefix or lies below one, `/api` covers `/api/x` but not `/apiary`;
+    /// matched like access rules, on the normalized path and ignoring case
+    pub fn is_route_disabled(&self, path: &str) -> bool {
+        let path = normalize_path(path);
+        self.disabled_routes
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .any(|prefix| is_under(&path, prefix))
+    }
+
+    pub fn disable_route(&self, prefix: String) {
+        self.disabled_routes.write().unwrap_or_else(PoisonError::into_inner).insert(prefix);
+    }
+
+    pub fn enable_route(&self, prefix: &str) {
+        self.disabled_routes.write().unwrap_or_else(PoisonError::into_inner).remove(prefix);
+    }
+
+    pub fn enable_all_routes(&self) {
+        self.disabled_routes.write().unwrap_or_else(PoisonError::into_inner).clear();
+    }
+
+    /// Returns the attempt's number, which doubles as the WebSocket's ID in logs
+    pub fn ws_upgrade_attempted(&self) -> u64 {
+        self.ws_upgrades_attempted.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    pub fn ws_upgrade_succeeded(&self) {
+        self.ws_upgrades_succeeded.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Registers WebSocket `id`, see `ws_upgrade_attempted`
+    pub fn websocket_opened(self: &Arc<Self>, id: u64, route: String, client: Option<SocketAddr>) -> WebSocketGuard {
+        let entry = Arc::new(WebSocketEntry {
+            route,
+            client,
+            opened: Instant::now(),
+            bytes_to_client: AtomicU64::new(0),
+            bytes_to_upstream: AtomicU64::new(0),
+        });
+        let mut websockets = self.websockets.write().unwrap_or_else(PoisonError::into_inner);
+        websockets.insert(id, entry.clone());
+        tracing::info!("WebSocket opened, {} open", websockets.len());
+        WebSocketGuard { state: self.clone(), id, entry }
+    }
+
+    /// Payload bytes of a relayed WebSocket message, close frames count as empty
+    pub fn update_ws_traffic(&self, bytes: usize) {
+        self.ws_traffic.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn websocket_traffic(&self) -> u64 {
+        self.ws_traffic.load(Ordering::Relaxed)
+    }
+
+    pub fn websockets(&self) -> Vec<WebSocketInfo> {
+        self.websockets
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(&id, entry)| WebSocketInfo {
+                id,
+                route: entry.route.clone(),
+                client: entry.client,
+                open_secs: entry.opened.elapsed().as_secs(),
+                bytes_to_client: entry.bytes_to_client.load(Ordering::Relaxed),
+                bytes_to_upstream: entry.bytes_to_upstream.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    pub fn ws_upgrade_rejected(&self, reason: WsRejection) {
+        let counter = match reason {
+            WsRejection::InvalidRequest => &self.ws_upgrades_invalid,
+            WsRejection::UpstreamFailed => &self.ws_upgrades_upstream_failed,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Proxied request body size, in bytes
+    pub fn record_request_size(&self, size: u64) {
+        self.request_sizes.record(size);
+    }
+
+    /// Proxied response body size, in bytes
+    pub fn record_response_size(&self, size: u64) {
+        self.response_sizes.record(size);
+    }
+
+    /// Request body bytes sent upstream for `path`
+    pub fn update_sent(&self, path: &str, bytes: u64) {
+        self.update_traffic(path, |traffic| {
+            traffic.sent.fetch_add(bytes, Ordering::Relaxed);
+        });
+    }
+
+    /// Response body bytes received from upstream for `path`
+    pub fn update_received(&self, path: &str, bytes: u64) {
+        self.update_traffic(path, |traffic| {
+            traffic.received.fetch_add(bytes, Ordering::Relaxed);
+        });
+    }
+
+    /// A request for `path` the upstream answered with `status`
+    pub fn record_response(&self, path: &str, status: u16) {
+        self.update_traffic(path, |traffic| {
+            traffic.requests.fetch_add(1, Ordering::Relaxed);
+            *traffic.statuses.lock().unwrap_or_else(PoisonError::into_inner).entry(status).or_default() += 1;
+        });
+    }
+
+    /// Known paths only take the read lock, the write lock is for adding a path
+    fn update_traffic(&self, path: &str, update: impl FnOnce(&PathCounters)) {
+        if let Some(entry) = self.traffic.read().unwrap_or_else(PoisonError::into_inner).get(path) {
+            return update(entry);
+        }
+        let mut traffic = self.traffic.write().unwrap_or_else(PoisonError::into_inner);
+        let key = if traffic.contains_key(path) || traffic.len() < MAX_TRAFFIC_PATHS { path } else { OTHER_PATHS };
+        update(traffic.entry(key.to_owned()).or_default())
+    }
+
+    /// A slot for one shadow request, held until it's done; `None` when all are taken
+    pub fn shadow_slot(&self) -> Option<OwnedSemaphorePermit> {
+        self.shadow_slots.0.clone().try_acquire_owned().ok()
+    }
+
+    /// Size of a response from the static mounts, in bytes
+    pub fn record_static_size(&self, size: u64) {
+        self.static_sizes.record(size);
+    }
+
+    pub fn stats(&self) -> Stats {
+        let uptime_secs = self.uptime_secs();
+        Stats {
+            upstream_connections_opened: self.upstream_connections.load(Ordering::Relaxed),
+            open_connections: self.open_connections(),
+            maintenance: self.maintenance(),
+            ready: self.ready(),
+            uptime_secs,
+            uptime: format_uptime(uptime_secs),
+            ws_upgrades: WsUpgradeStats {
+                attempted: self.ws_upgrades_attempted.load(Ordering::Relaxed),
+                succeeded: self.ws_upgrades_succeeded.load(Ordering::Relaxed),
+                rejected_invalid_request: self.ws_upgrades_invalid.load(Ordering::Relaxed),
+                rejected_upstream_failed: self.ws_upgrades_upstream_failed.load(Ordering::Relaxed),
+            },
+            open_websockets: self.open_websockets(),
+            websocket_traffic: self.websocket_traffic(),
+            request_body_sizes: self.request_sizes.stats(),
+            response_body_sizes: self.response_sizes.stats(),
+            static_body_sizes: self.static_sizes.stats(),
+            traffic: self
+                .traffic
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .map(|(path, counters)| (path.clone(), counters.snapshot()))
+                .collect(),
+        }
+    }
+}
+
+/// Days, hours and minutes, leading zero units left out: "3d 4h 12m", "4h 0m", "0m"
+fn format_uptime(secs: u64) -> String {
+    let (days, hours, minutes) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+    match (days, hours) {
+        (0, 0) => format!("{}m", minutes),
+        (0, _) => format!("{}h {}m", hours, minutes),
+        _ => format!("{}d {}h {}m", days, hours, minutes),
+    }
+}
+
+/// Whether `path` equals `prefix` or continues it with a new segment, ignoring case
+fn is_under(path: &str, prefix: &str) -> bool {
+    match path.get(..prefix.len()) {
+        Some(head) if head.eq_ignore_ascii_case(prefix) => {
+            let rest = &path[prefix.len()..];
+            rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/')
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_prefixes_match_whole_segments() {
+        assert!(is_under("/api", "/api"));
+        assert!(is_under("/api/flows", "/api"));
+        assert!(is_under("/api/flows", "/api/"));
+        assert!(!is_under("/apiary", "/api"));
+        assert!(!is_under("/ap", "/api"));
+        assert!(is_under("/API/flows", "/api"));
+    }
+
+    #[test]
+    fn other_spellings_of_a_disabled_route_are_disabled() {
+        let state = State::default();
+        state.disable_route("/api/export".to_owned());
+        for path in ["/api/export", "/API/export", "/api//export/x", "/api/%65xport", "/api/x/../export"] {
+            assert!(state.is_route_disabled(path), "{}", path);
+        }
+        assert!(!state.is_route_disabled("/api/exports"));
+    }
+
+    #[test]
+    fn uptime_is_formatted_down_to_minutes() {
+        assert_eq!(format_uptime(59), "0m");
+        assert_eq!(format_uptime(12 * 60 + 30), "12m");
+        assert_eq!(format_uptime(4 * 3600), "4h 0m");
+        assert_eq!(format_uptime(3 * 86400 + 4 * 3600 + 12 * 60), "3d 4h 12m");
+        assert_eq!(format_uptime(86400), "1d 0h 0m");
+    }
+
+    #[test]
+    fn traffic_paths_past_the_cap_fold_into_other() {
+        let state = State::default();
+        for i in 0..MAX_TRAFFIC_PATHS + 5 {
+            state.update_sent(&format!("/p{i}"), 1);
+        }
+        state.update_sent("/p0", 1);
+
+        let traffic = state.stats().traffic;
+        assert_eq!(traffic.len(), MAX_TRAFFIC_PATHS + 1);
+        assert_eq!(traffic["/p0"].sent, 2);
+        assert_eq!(traffic[OTHER_PATHS].sent, 5);
+    }
+
+    #[test]
+    fn responses_count_requests_and_statuses() {
+        let state = State::default();
+        state.record_response("/x", 200);
+        state.record_response("/x", 200);
+        state.record_response("/x", 502);
+        state.update_received("/x", 10);
+
+        let traffic = &state.stats().traffic["/x"];
+        assert_eq!(traffic.requests, 3);
+        assert_eq!(traffic.received, 10);
+        assert_eq!(traffic.statuses, BTreeMap::from([(200, 2), (502, 1)]));
+    }
+}
@@ -0,0 +1,161 @@
+//! In-process harness for router tests: a TLS mock upstream and a client that
+//! dispatches requests to a `Router` without binding a socket
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{atomic::{AtomicUsize, Ordering}, Arc, Mutex},
+};
+
+use axum::{body::Body, extract::Request, response::Response, Router};
+use config::{File, FileFormat};
+use http_body_util::BodyExt;
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+    service::TowerToHyperService,
+};
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use tokio::{io::AsyncReadExt, net::TcpListener};
+use tokio_rustls::TlsAcceptor;
+use tower::ServiceExt;
+use tracing::subscriber::DefaultGuard;
+
+use crate::{
+    cfg::{load_config, Cfg},
+    router::get_router,
+    state::State,
+    tls::{build_client_config, build_https_client},
+};
+
+/// Self-signed for 127.0.0.1, the proxy under test runs with `insecure_tls`
+const UPSTREAM_CERT: &[u8] = include_bytes!("testdata/upstream.pem");
+const UPSTREAM_KEY: &[u8] = include_bytes!("testdata/upstream.key");
+
+/// A config proxying to `upstream`, `extra` is appended to the YAML as is
+pub fn test_config(upstream: SocketAddr, extra: &str) -> Arc<Cfg> {
+    let yaml = format!(
+        "port: 0\nsourcedata: /nonexistent\nhelp: /nonexistent\nhost: {}\npagrid: true\ninsecure_tls: true\n{}",
+        upstream, extra
+    );
+    load_config(File::from_str(&yaml, FileFormat::Yaml)).unwrap()
+}
+
+/// The proxy's router as `main` builds it, with fresh state
+pub fn proxy_router(cfg: &Arc<Cfg>) -> Router {
+    proxy_router_with_state(cfg, Arc::new(State::default()))
+}
+
+/// Like `proxy_router`, accounting into `state`
+pub fn proxy_router_with_state(cfg: &Arc<Cfg>, state: Arc<State>) -> Router {
+    let tls_config = build_client_config(cfg, true).unwrap();
+    let client = build_https_client(cfg, tls_config.clone(), state.clone()).unwrap();
+    get_router(cfg.clone(), client, Arc::new(tls_config), state).unwrap()
+}
+
+fn upstream_acceptor() -> TlsAcceptor {
+    let certs = CertificateDer::pem_slice_iter(UPSTREAM_CERT).collect::<Result<Vec<_>, _>>().unwrap();
+    let key = PrivateKeyDer::from_pem_slice(UPSTREAM_KEY).unwrap();
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// Serves `app` over TLS on a free local port, WebSocket upgrades included
+pub async fn mock_upstream(app: Router) -> SocketAddr {
+    let acceptor = upstream_acceptor();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            let app = app.clone();
+            tokio::spawn(async move {
+                let Ok(stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let service = TowerToHyperService::new(app.map_request(|req: Request<Incoming>| req.map(Body::new)));
+                let _ = auto::Builder::new(TokioExecutor::new())
+                    .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    addr
+}
+
+/// An upstream that reads each request and closes the connection without answering,
+/// counting the requests it got
+pub async fn mock_upstream_hanging_up() -> (SocketAddr, Arc<AtomicUsize>) {
+    let acceptor = upstream_acceptor();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let requests = Arc::new(AtomicUsize::new(0));
+    let counter = requests.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let Ok(mut stream) = acceptor.accept(stream).await else {
+                continue;
+            };
+            if stream.read(&mut [0; 4096]).await.is_ok_and(|read| read > 0) {
+                counter.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    });
+    (addr, requests)
+}
+
+/// Dispatches requests to a router in-process with `oneshot`, no socket involved
+pub struct TestClient(Router);
+
+impl TestClient {
+    pub fn new(router: Router) -> Self {
+        Self(router)
+    }
+
+    pub async fn request(&self, request: Request) -> Response {
+        self.0.clone().oneshot(request).await.unwrap()
+    }
+
+    pub async fn get(&self, uri: &str) -> Response {
+        self.request(Request::get(uri).body(Body::empty()).unwrap()).await
+    }
+}
+
+pub async fn body_text(response: Response) -> String {
+    let body = response.into_body().collect().await.unwrap().to_bytes();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+/// Log output of the current thread, see `capture_logs`
+#[derive(Clone, Default)]
+pub struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
+
+impl CapturedLogs {
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).into_owned()
+    }
+}
+
+impl io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Records what's logged on this thread until the guard is dropped; tasks spawned by the test
+/// log here too on a `current_thread` runtime
+pub fn capture_logs() -> (CapturedLogs, DefaultGuard) {
+    let logs = CapturedLogs::default();
+    let writer = logs.clone();
+    let subscriber = tracing_subscriber::fmt().with_ansi(false).with_writer(move || writer.clone()).finish();
+    (logs, tracing::subscriber::set_default(subscriber))
+}
@@ -0,0 +1,85 @@
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use http::StatusCode;
+use hyper::body::{Body as HttpBody, Frame, SizeHint};
+use tokio::time::{Instant, Sleep};
+
+/// Hard ceiling on the whole request, response body included; dropping the
+/// inner future cancels the upstream request
+pub async fn request_timeout(State(timeout): State<Duration>, req: Request, next: Next) -> Response {
+    let deadline = Instant::now() + timeout;
+    let method = req.method().clone();
+    let uri = req.uri().clone();
+
+    let response = match tokio::time::timeout_at(deadline, next.run(req)).await {
+        Ok(response) => response,
+        Err(_) => {
+            tracing::warn!("{} {} timed out after {:?}", method, uri, timeout);
+            return (StatusCode::GATEWAY_TIMEOUT, "Request timed out").into_response();
+        }
+    };
+
+    // headers are already sent by the time the body runs late, all that's left is to abort it
+    let target = format!("{} {}", method, uri);
+    response.map(|body| Body::new(DeadlineBody {
+        inner: body,
+        deadline: Box::pin(tokio::time::sleep_until(deadline)),
+        timeout,
+        target: Some(target),
+    }))
+}
+
+/// Passes a body through until `deadline`, then fails it; keeps the size hint so
+/// fixed-size responses still go out with a Content-Length
+struct DeadlineBody {
+    inner: Body,
+    deadline: Pin<Box<Sleep>>,
+    timeout: Duration,
+    /// Taken once the deadline hit, the body is over after that error
+    target: Option<String>,
+}
+
+impl HttpBody for DeadlineBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if self.target.is_none() {
+            return Poll::Ready(None);
+        }
+        if let Poll::Ready(frame) = Pin::new(&mut self.inner).poll_frame(cx) {
+            return Poll::Ready(frame);
+        }
+        if self.deadline.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        let target = self.target.take().unwrap_or_default();
+        tracing::warn!("{} response body timed out after {:?}", target, self.timeout);
+        let e = io::Error::new(io::ErrorKind::TimedOut, "request timed out");
+        Poll::Ready(Some(Err(axum::Error::new(e))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.target.is_none() || self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -1,17 +1,20 @@
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 
+use anyhow::Context;
 use hyper_util::{
-    client::legacy::{connect::HttpConnector, Client},
+    client::legacy::Client,
     rt::TokioExecutor,
 };
 use rustls::{
     client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
-    pki_types::{CertificateDer, ServerName, UnixTime},
+    pki_types::{pem::PemObject, CertificateDer, ServerName, UnixTime},
     DigitallySignedStruct,
 };
 
+use crate::{cfg::Cfg, connector::CountingConnector, state::State};
+
 pub type HTTPSClient =
-    Client<hyper_rustls::HttpsConnector<HttpConnector>, axum::body::Body>;
+    Client<hyper_rustls::HttpsConnector<CountingConnector>, axum::body::Body>;
 
 #[derive(Debug)]
 struct DummyVerifier {}
@@ -65,25 +68,93 @@ impl ServerCertVerifier for DummyVerifier {
     }
 }
 
-pub fn build_client_config() -> rustls::ClientConfig {
-    let verifier = Arc::new(DummyVerifier {});
-    rustls::ClientConfig::builder()
-        .dangerous()
-        .with_custom_certificate_verifier(verifier)
-        .with_no_client_auth()
+/// Verifies upstream certificates against `cfg.ca_bundle`, or the platform's root store
+/// without one, unless `insecure`
+pub fn build_client_config(cfg: &Cfg, insecure: bool) -> anyhow::Result<rustls::ClientConfig> {
+    if insecure {
+        if let Some(ca_bundle) = &cfg.ca_bundle {
+            tracing::warn!("Ignoring ca_bundle {} since certificates aren't verified", ca_bundle.display());
+        }
+        let verifier = Arc::new(DummyVerifier {});
+        return Ok(rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(verifier)
+            .with_no_client_auth());
+    }
+
+    let roots = match &cfg.ca_bundle {
+        Some(ca_bundle) => load_ca_bundle(ca_bundle)?,
+        None => load_native_roots()?,
+    };
+    Ok(rustls::ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+fn load_ca_bundle(path: &Path) -> anyhow::Result<rustls::RootCertStore> {
+    let pem = std::fs::read(path)
+        .with_context(|| format!("Could not read ca_bundle {}", path.display()))?;
+    let mut roots = rustls::RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(&pem) {
+        let cert = cert.with_context(|| format!("Invalid PEM in ca_bundle {}", path.display()))?;
+        roots.add(cert)
+            .with_context(|| format!("Invalid certificate in ca_bundle {}", path.display()))?;
+    }
+    if roots.is_empty() {
+        anyhow::bail!("ca_bundle {} contains no certificates", path.display());
+    }
+    tracing::info!("Verifying upstream certificates against {} from {}", roots.len(), path.display());
+    Ok(roots)
+}
+
+fn load_native_roots() -> anyhow::Result<rustls::RootCertStore> {
+    let native = rustls_native_certs::load_native_certs();
+    for e in &native.errors {
+        tracing::warn!("Could not load a platform root certificate: {}", e);
+    }
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(native.certs);
+    if added == 0 {
+        anyhow::bail!("No usable root certificates found on this system, upstream TLS can't be verified");
+    }
+    tracing::debug!("Loaded {} platform root certificates ({} ignored)", added, ignored);
+    Ok(roots)
 }
 
-pub fn build_tls_connector() -> anyhow::Result<hyper_rustls::HttpsConnector<HttpConnector>> {
-    let config = build_client_config();
-    Ok(hyper_rustls::HttpsConnectorBuilder::new()
-        .with_tls_config(config)
-        .https_or_http()
-        .enable_http1()
-        .build())
+pub fn build_tls_connector(
+    cfg: &Cfg,
+    mut config: rustls::ClientConfig,
+    state: Arc<State>
+) -> anyhow::Result<hyper_rustls::HttpsConnector<CountingConnector>> {
+    let connector = CountingConnector::new(state);
+
+    // WebSocket connections keep the default so they always get HTTP/1.1
+    let connector = if let Some(alpn_protocols) = &cfg.alpn_protocols {
+        config.alpn_protocols = alpn_protocols
+            .iter()
+            .map(|protocol| protocol.as_bytes().to_vec())
+            .collect();
+        // the builder refuses a config with ALPN already set, and this one allows plain http
+        let mut connector = hyper_rustls::HttpsConnector::from((connector, config));
+        connector.enforce_https();
+        connector
+    } else {
+        let builder = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_tls_config(config)
+            .https_only()
+            .enable_http1();
+        // offers h2 over ALPN, upstreams that don't pick it keep getting HTTP/1.1
+        if cfg.http2 {
+            builder.enable_http2().wrap_connector(connector)
+        } else {
+            builder.wrap_connector(connector)
+        }
+    };
+    Ok(connector)
 }
 
-pub fn build_https_client() -> anyhow::Result<HTTPSClient> {
-    let connector = build_tls_connector()?;
+pub fn build_https_client(cfg: &Cfg, config: rustls::ClientConfig, state: Arc<State>) -> anyhow::Result<HTTPSClient> {
+    let connector = build_tls_connector(cfg, config, state)?;
     let client = Client::builder(TokioExecutor::new()).build(connector);
     Ok(client)
 }
@@ -1,5 +1,10 @@
-use tokio_tungstenite::tungstenite::Message as TungsteniteWsMessage;
-use axum::extract::ws::Message as AxumWsMessage;
+use tokio_tungstenite::tungstenite::{
+    protocol::CloseFrame as TungsteniteCloseFrame,
+    Message as TungsteniteWsMessage,
+};
+use axum::extract::ws::{CloseFrame as AxumCloseFrame, Message as AxumWsMessage};
+
+use crate::cfg::{Direction, TextReplacement};
 
 pub fn axum_to_tungstein(msg: AxumWsMessage) -> TungsteniteWsMessage {
     match msg {
@@ -7,7 +12,10 @@ pub fn axum_to_tungstein(msg: AxumWsMessage) -> TungsteniteWsMessage {
         AxumWsMessage::Binary(data) => TungsteniteWsMessage::Binary(data),
         AxumWsMessage::Ping(ping) => TungsteniteWsMessage::Ping(ping),
         AxumWsMessage::Pong(pong) => TungsteniteWsMessage::Pong(pong),
-        AxumWsMessage::Close(_) => TungsteniteWsMessage::Close(None),
+        AxumWsMessage::Close(frame) => TungsteniteWsMessage::Close(frame.map(|frame| TungsteniteCloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason,
+        })),
     }
 }
 
@@ -17,7 +25,93 @@ pub fn tungstein_to_axum(msg: TungsteniteWsMessage) -> Option<AxumWsMessage> {
         TungsteniteWsMessage::Binary(data) => AxumWsMessage::Binary(data).into(),
         TungsteniteWsMessage::Ping(data) => AxumWsMessage::Ping(data).into(),
         TungsteniteWsMessage::Pong(data) => AxumWsMessage::Pong(data).into(),
-        TungsteniteWsMessage::Close(_) => AxumWsMessage::Close(None).into(),
+        TungsteniteWsMessage::Close(frame) => AxumWsMessage::Close(frame.map(|frame| AxumCloseFrame {
+            code: frame.code.into(),
+            reason: frame.reason,
+        })).into(),
         TungsteniteWsMessage::Frame(_) => None,
     }
-}
\ No newline at end of file
+}
+
+/// Payload size of a message, close frames count as empty
+pub fn payload_len(msg: &AxumWsMessage) -> usize {
+    match msg {
+        AxumWsMessage::Text(text) => text.len(),
+        AxumWsMessage::Binary(data) | AxumWsMessage::Ping(data) | AxumWsMessage::Pong(data) => data.len(),
+        AxumWsMessage::Close(_) => 0,
+    }
+}
+
+/// Applies the replacements configured for `direction` to a text frame, in order;
+/// every rule scans the whole message, so each one costs a pass over every text frame
+pub fn replace_text(text: String, replacements: &[TextReplacement], direction: Direction) -> String {
+    replacements
+        .iter()
+        .filter(|replacement| replacement.direction.includes(direction))
+        .fold(text, |text, replacement| {
+            if text.contains(&replacement.find) {
+                text.replace(&replacement.find, &replacement.replace)
+            } else {
+                text
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+
+    use super::*;
+
+    fn axum_close(code: u16, reason: &str) -> AxumWsMessage {
+        AxumWsMessage::Close(Some(AxumCloseFrame { code, reason: reason.to_owned().into() }))
+    }
+
+    fn tungstenite_close(code: u16, reason: &str) -> TungsteniteWsMessage {
+        TungsteniteWsMessage::Close(Some(TungsteniteCloseFrame {
+            code: CloseCode::from(code),
+            reason: reason.to_owned().into(),
+        }))
+    }
+
+    #[test]
+    fn close_frames_keep_code_and_reason_to_upstream() {
+        assert_eq!(
+            axum_to_tungstein(axum_close(1011, "backend restarting")),
+            tungstenite_close(1011, "backend restarting")
+        );
+        assert_eq!(axum_to_tungstein(AxumWsMessage::Close(None)), TungsteniteWsMessage::Close(None));
+    }
+
+    #[test]
+    fn close_frames_keep_code_and_reason_to_client() {
+        match tungstein_to_axum(tungstenite_close(1011, "backend restarting")) {
+            Some(AxumWsMessage::Close(Some(frame))) => {
+                assert_eq!(frame.code, 1011);
+                assert_eq!(frame.reason, "backend restarting");
+            }
+            other => panic!("expected a close frame, got {:?}", other),
+        }
+        assert!(matches!(tungstein_to_axum(TungsteniteWsMessage::Close(None)), Some(AxumWsMessage::Close(None))));
+    }
+
+    #[test]
+    fn close_frames_survive_a_round_trip() {
+        let relayed = tungstein_to_axum(axum_to_tungstein(axum_close(1011, "backend restarting")));
+        assert!(matches!(
+            relayed,
+            Some(AxumWsMessage::Close(Some(frame))) if frame.code == 1011 && frame.reason == "backend restarting"
+        ));
+        assert_eq!(payload_len(&axum_close(1011, "backend restarting")), 0);
+    }
+
+    #[test]
+    fn replacements_apply_to_their_direction_only() {
+        let replacements = [
+            TextReplacement { find: "a".into(), replace: "b".into(), direction: Direction::ToClient },
+            TextReplacement { find: "b".into(), replace: "c".into(), direction: Direction::Both },
+        ];
+        assert_eq!(replace_text("a".into(), &replacements, Direction::ToClient), "c");
+        assert_eq!(replace_text("a".into(), &replacements, Direction::ToUpstream), "a");
+    }
+}